@@ -1,3 +1,5 @@
+use crate::queue::QPrio;
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Sqe {
@@ -16,44 +18,142 @@ pub struct Sqe {
     pub cdw15: u32
 }
 
+pub const PSDT_PRP: u8 = 0;
+pub const PSDT_SGL: u8 = 1;
+pub const PSDT_SGL_MPTR: u8 = 2;
+
+pub const FUSE_NONE: u8 = 0;
+pub const FUSE_FIRST: u8 = 1;
+pub const FUSE_SECOND: u8 = 2;
+
+#[derive(Clone, Copy)]
 pub struct Cmd {
     pub opc: u8,
+    pub fuse: u8,
+    pub psdt: u8,
     pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
     pub prp1: u64,
     pub prp2: u64,
     pub cdw10: u32,
     pub cdw11: u32,
-    pub cdw12: u32
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataDir {
+    ToDev,
+    FromDev
+}
+
+pub struct Passthru<'a> {
+    pub cmd: Cmd,
+    data: Option<(&'a mut [u8], DataDir)>,
+    meta: Option<&'a mut [u8]>
+}
+
+impl<'a> Passthru<'a> {
+    pub fn new(cmd: Cmd) -> Self {
+        return Self { cmd, data: None, meta: None };
+    }
+
+    pub fn set_data(&mut self, buf: &'a mut [u8], dir: DataDir) -> &mut Self {
+        self.data = Some((buf, dir));
+        return self;
+    }
+
+    pub fn set_meta(&mut self, buf: &'a mut [u8]) -> &mut Self {
+        self.meta = Some(buf);
+        return self;
+    }
+
+    pub fn dir(&self) -> Option<DataDir> {
+        return self.data.as_ref().map(|(_, dir)| *dir);
+    }
+
+    pub fn data(&self) -> Option<(usize, usize)> {
+        return self.data.as_ref().map(|(buf, _)| (buf.as_ptr() as usize, buf.len()));
+    }
+
+    pub fn meta(&self) -> Option<(usize, usize)> {
+        return self.meta.as_ref().map(|buf| (buf.as_ptr() as usize, buf.len()));
+    }
 }
 
 impl Cmd {
     pub fn new(opc: u8) -> Self {
         return Self {
             opc,
+            fuse: FUSE_NONE,
+            psdt: PSDT_PRP,
             nsid: 0,
+            cdw2: 0,
+            cdw3: 0,
+            mptr: 0,
             prp1: 0,
             prp2: 0,
             cdw10: 0,
             cdw11: 0,
-            cdw12: 0
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0
+        };
+    }
+
+    pub fn from_sqe(sqe: &Sqe) -> Self {
+        return Self {
+            opc: sqe.cdw0 as u8,
+            fuse: ((sqe.cdw0 >> 8) & 0x3) as u8,
+            psdt: ((sqe.cdw0 >> 14) & 0x3) as u8,
+            nsid: sqe.nsid,
+            cdw2: sqe.cdw2,
+            cdw3: sqe.cdw3,
+            mptr: sqe.mptr,
+            prp1: sqe.prp1,
+            prp2: sqe.prp2,
+            cdw10: sqe.cdw10,
+            cdw11: sqe.cdw11,
+            cdw12: sqe.cdw12,
+            cdw13: sqe.cdw13,
+            cdw14: sqe.cdw14,
+            cdw15: sqe.cdw15
         };
     }
 
+    pub fn with_psdt(mut self, psdt: u8) -> Self {
+        self.psdt = psdt;
+        return self;
+    }
+
+    pub fn with_fuse(mut self, fuse: u8) -> Self {
+        self.fuse = fuse;
+        return self;
+    }
+
     pub fn to_sqe(&self, cid: u16) -> Sqe {
         return Sqe {
-            cdw0: (cid as u32) << 16 | self.opc as u32,
+            cdw0: (cid as u32) << 16
+                | ((self.psdt & 0x3) as u32) << 14
+                | ((self.fuse & 0x3) as u32) << 8
+                | self.opc as u32,
             nsid: self.nsid,
-            cdw2: 0,
-            cdw3: 0,
-            mptr: 0,
+            cdw2: self.cdw2,
+            cdw3: self.cdw3,
+            mptr: self.mptr,
             prp1: self.prp1,
             prp2: self.prp2,
             cdw10: self.cdw10,
             cdw11: self.cdw11,
             cdw12: self.cdw12,
-            cdw13: 0,
-            cdw14: 0,
-            cdw15: 0
+            cdw13: self.cdw13,
+            cdw14: self.cdw14,
+            cdw15: self.cdw15
         };
     }
 
@@ -79,41 +179,44 @@ impl Cmd {
         return cmd;
     }
 
-    pub fn cq_create(qid: u16, qsize: u16, prp1: u64) -> Self {
+    pub fn cq_create(qid: u16, qsize: u16, prp1: u64, pc: bool, iv: Option<u16>) -> Self {
         let mut cmd = Self::new(0x05);
         cmd.prp1 = prp1;
         cmd.cdw10 = ((qsize - 1) as u32) << 16 | qid as u32;
-        cmd.cdw11 = 0x1;
+        cmd.cdw11 = match iv {
+            Some(iv) => (iv as u32) << 16 | 0x2,
+            None => 0
+        } | pc as u32;
         return cmd;
     }
 
-    pub fn sq_create(qid: u16, qsize: u16, cqid: u16, prp1: u64) -> Self {
+    pub fn sq_create(qid: u16, qsize: u16, cqid: u16, prp1: u64, pc: bool, prio: QPrio) -> Self {
         let mut cmd = Self::new(0x01);
         cmd.prp1 = prp1;
         cmd.cdw10 = ((qsize - 1) as u32) << 16 | qid as u32;
-        cmd.cdw11 = (cqid as u32) << 16 | 0x1;
+        cmd.cdw11 = (cqid as u32) << 16 | (prio as u32) << 1 | pc as u32;
         return cmd;
     }
 
-    pub fn read(nsid: u32, lba: u64, nlb: u16, prp1: u64, prp2: u64) -> Self {
+    pub fn read(nsid: u32, lba: u64, nlb: u32, prp1: u64, prp2: u64) -> Self {
         let mut cmd = Self::new(0x02);
         cmd.nsid = nsid;
         cmd.prp1 = prp1;
         cmd.prp2 = prp2;
         cmd.cdw10 = lba as u32;
         cmd.cdw11 = (lba >> 32) as u32;
-        cmd.cdw12 = (nlb - 1) as u32;
+        cmd.cdw12 = (nlb - 1) & 0xFFFF;
         return cmd;
     }
 
-    pub fn write(nsid: u32, lba: u64, nlb: u16, prp1: u64, prp2: u64) -> Self {
+    pub fn write(nsid: u32, lba: u64, nlb: u32, prp1: u64, prp2: u64) -> Self {
         let mut cmd = Self::new(0x01);
         cmd.nsid = nsid;
         cmd.prp1 = prp1;
         cmd.prp2 = prp2;
         cmd.cdw10 = lba as u32;
         cmd.cdw11 = (lba >> 32) as u32;
-        cmd.cdw12 = (nlb - 1) as u32;
+        cmd.cdw12 = (nlb - 1) & 0xFFFF;
         return cmd;
     }
 
@@ -199,23 +302,23 @@ impl Cmd {
         return cmd;
     }
 
-    pub fn wr_zero(nsid: u32, slba: u64, nlb: u16) -> Self {
+    pub fn wr_zero(nsid: u32, slba: u64, nlb: u32) -> Self {
         let mut cmd = Self::new(0x08);
         cmd.nsid = nsid;
         cmd.cdw10 = slba as u32;
         cmd.cdw11 = (slba >> 32) as u32;
-        cmd.cdw12 = nlb as u32;
+        cmd.cdw12 = (nlb - 1) & 0xFFFF;
         return cmd;
     }
 
-    pub fn cmp(nsid: u32, slba: u64, nlb: u16, prp1: u64, prp2: u64) -> Self {
+    pub fn cmp(nsid: u32, slba: u64, nlb: u32, prp1: u64, prp2: u64) -> Self {
         let mut cmd = Self::new(0x05);
         cmd.nsid = nsid;
         cmd.prp1 = prp1;
         cmd.prp2 = prp2;
         cmd.cdw10 = slba as u32;
         cmd.cdw11 = (slba >> 32) as u32;
-        cmd.cdw12 = nlb as u32;
+        cmd.cdw12 = (nlb - 1) & 0xFFFF;
         return cmd;
     }
 
@@ -236,12 +339,12 @@ impl Cmd {
         return cmd;
     }
 
-    pub fn verify(nsid: u32, slba: u64, nlb: u16) -> Self {
+    pub fn verify(nsid: u32, slba: u64, nlb: u32) -> Self {
         let mut cmd = Self::new(0x0C);
         cmd.nsid = nsid;
         cmd.cdw10 = slba as u32;
         cmd.cdw11 = (slba >> 32) as u32;
-        cmd.cdw12 = nlb as u32;
+        cmd.cdw12 = (nlb - 1) & 0xFFFF;
         return cmd;
     }
 
@@ -1,7 +1,246 @@
-use crate::{cmd::Cmd, id::CtrlId, queue::{Cqe, Queue}, reg, Dma, LogErr, LogSmart, NVMeError, Result};
-use core::{hint::spin_loop, sync::atomic::{AtomicBool, AtomicU16, Ordering}};
+use crate::{
+    cmd::{Cmd, DataDir, Passthru, FUSE_FIRST, FUSE_SECOND, PSDT_PRP, PSDT_SGL, PSDT_SGL_MPTR}, id::{CtrlId, SanitizeKind, ONCS_COMPARE, ONCS_DSM, ONCS_VERIFY, ONCS_WR_ZERO},
+    queue::{Cq, Cqe, QPrio, Queue}, ram::{build_prp, build_sgl, sgl_descs, BouncePool, DmaPool, PrpList},
+    reg::{self, CapReg, CcReg, CstsReg},
+    time::Deadline, ArbConfig, BounceStats, Clock, Dma, LogErr, LogSmart, Mmio, NVMeError, Result
+};
+use core::{
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{fence, AtomicBool, AtomicU16, AtomicU64, Ordering}
+};
 use alloc::{string::{String, ToString}, sync::Arc, collections::BTreeMap, vec::Vec};
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArbMode {
+    RoundRobin,
+    WeightedRoundRobin
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdSet {
+    Nvm,
+    AllIo,
+    AdminOnly
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdClass {
+    Admin,
+    Read,
+    Write,
+    Other
+}
+
+impl CmdClass {
+    pub fn of_io(cmd: &Cmd) -> Self {
+        return match cmd.opc {
+            0x01 => CmdClass::Write,
+            0x02 => CmdClass::Read,
+            _ => CmdClass::Other
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    admin: u8,
+    read: u8,
+    write: u8,
+    other: u8
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        return Self { admin: 0, read: 0, write: 0, other: 0 };
+    }
+
+    pub fn set_retries(&mut self, class: CmdClass, n: u8) -> &mut Self {
+        match class {
+            CmdClass::Admin => self.admin = n,
+            CmdClass::Read => self.read = n,
+            CmdClass::Write => self.write = n,
+            CmdClass::Other => self.other = n
+        }
+        return self;
+    }
+
+    pub fn set_all(&mut self, n: u8) -> &mut Self {
+        (self.admin, self.read, self.write, self.other) = (n, n, n, n);
+        return self;
+    }
+
+    pub fn retries(&self, class: CmdClass) -> u8 {
+        return match class {
+            CmdClass::Admin => self.admin,
+            CmdClass::Read => self.read,
+            CmdClass::Write => self.write,
+            CmdClass::Other => self.other
+        };
+    }
+
+    pub fn enabled(&self) -> bool {
+        return (self.admin | self.read | self.write | self.other) != 0;
+    }
+}
+
+#[derive(Clone)]
+pub struct CtrlConfig {
+    adm_depth: Option<usize>,
+    io_cnt: u16,
+    io_depth: Option<usize>,
+    pg_sz: Option<usize>,
+    host_pg: usize,
+    arb: ArbMode,
+    css: CmdSet,
+    async_ev: bool,
+    bounce: Option<usize>,
+    retry: RetryPolicy,
+    clock: Arc<dyn Clock>
+}
+
+impl CtrlConfig {
+    pub fn new<C: Clock + 'static>(clock: C) -> Self {
+        return Self {
+            adm_depth: None,
+            io_cnt: 1,
+            io_depth: None,
+            pg_sz: None,
+            host_pg: 4096,
+            arb: ArbMode::RoundRobin,
+            css: CmdSet::Nvm,
+            async_ev: false,
+            bounce: None,
+            retry: RetryPolicy::new(),
+            clock: Arc::new(clock)
+        };
+    }
+
+    pub fn set_admin_depth(&mut self, depth: usize) -> &mut Self {
+        self.adm_depth = Some(depth);
+        return self;
+    }
+
+    pub fn set_io_queues(&mut self, count: u16) -> &mut Self {
+        self.io_cnt = count;
+        return self;
+    }
+
+    pub fn set_io_depth(&mut self, depth: usize) -> &mut Self {
+        self.io_depth = Some(depth);
+        return self;
+    }
+
+    pub fn set_page_size(&mut self, size: usize) -> &mut Self {
+        self.pg_sz = Some(size);
+        return self;
+    }
+
+    pub fn set_host_page_size(&mut self, size: usize) -> &mut Self {
+        self.host_pg = size;
+        return self;
+    }
+
+    pub fn set_arb(&mut self, arb: ArbMode) -> &mut Self {
+        self.arb = arb;
+        return self;
+    }
+
+    pub fn set_cmd_set(&mut self, css: CmdSet) -> &mut Self {
+        self.css = css;
+        return self;
+    }
+
+    pub fn set_async_events(&mut self, enable: bool) -> &mut Self {
+        self.async_ev = enable;
+        return self;
+    }
+
+    pub fn set_bounce(&mut self, keep: Option<usize>) -> &mut Self {
+        self.bounce = keep;
+        return self;
+    }
+
+    pub fn set_retry(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = retry;
+        return self;
+    }
+
+    fn check(&self, cap: CapReg) -> Result<(usize, usize, usize)> {
+        let mqes = (cap.mqes() as usize + 1).min(u16::MAX as usize);
+        let adm_sz = self.adm_depth.unwrap_or(mqes.min(4096));
+        let io_sz = self.io_depth.unwrap_or(mqes.min(256));
+        if !(2..=mqes.min(4096)).contains(&adm_sz) || !(2..=mqes).contains(&io_sz) {
+            return Err(NVMeError::InvCfg);
+        }
+        if self.io_cnt == 0 && self.css != CmdSet::AdminOnly {
+            return Err(NVMeError::InvCfg);
+        }
+
+        let min_pg = 4096 << cap.mpsmin();
+        let max_pg = 4096 << cap.mpsmax();
+        if !self.host_pg.is_power_of_two() || self.host_pg < 4096 {
+            return Err(NVMeError::InvCfg);
+        }
+
+        let pg_sz = self.pg_sz.unwrap_or(self.host_pg.clamp(min_pg, max_pg));
+        if !pg_sz.is_power_of_two() || pg_sz < min_pg || pg_sz > max_pg {
+            return Err(NVMeError::InvCfg);
+        }
+
+        let css_ok = match self.css {
+            CmdSet::Nvm => cap.css_nvm(),
+            CmdSet::AllIo => cap.css_iocs(),
+            CmdSet::AdminOnly => cap.css_noio()
+        };
+        if !css_ok || (self.arb == ArbMode::WeightedRoundRobin && !cap.ams_wrr()) {
+            return Err(NVMeError::InvCfg);
+        }
+
+        return Ok((adm_sz, io_sz, pg_sz));
+    }
+}
+
+const ADM_BUFS: usize = 2;
+
+type QueueMap<A> = Mutex<BTreeMap<u16, Arc<Queue<A>>>>;
+type Cqs<A> = BTreeMap<u16, (Arc<Cq<A>>, bool)>;
+type CqMap<A> = Mutex<Cqs<A>>;
+
+fn irq_bit(vector: u16) -> u64 {
+    return 1 << vector.min(63);
+}
+
+struct CqsGuard<'a, A: Dma, M: Mmio> {
+    ctrl: &'a Ctrl<A, M>,
+    map: Option<MutexGuard<'a, Cqs<A>>>
+}
+
+impl<A: Dma, M: Mmio> Deref for CqsGuard<'_, A, M> {
+    type Target = Cqs<A>;
+
+    fn deref(&self) -> &Self::Target {
+        return self.map.as_ref().unwrap();
+    }
+}
+
+impl<A: Dma, M: Mmio> DerefMut for CqsGuard<'_, A, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        return self.map.as_mut().unwrap();
+    }
+}
+
+impl<A: Dma, M: Mmio> Drop for CqsGuard<'_, A, M> {
+    fn drop(&mut self) {
+        self.map = None;
+        fence(Ordering::SeqCst);
+        if self.ctrl.irq_pending.load(Ordering::SeqCst) != 0 {
+            self.ctrl.irq_drain();
+        }
+    }
+}
 
 pub struct CtrlData {
     pub serial: String,
@@ -9,271 +248,871 @@ pub struct CtrlData {
     pub firm: String,
     pub mts: usize,
     pub mqe: u16,
-    pub min_pg: usize
+    pub min_pg: usize,
+    pub sgl_align: Option<usize>,
+    pub crdt: [u16; 3]
+}
+
+pub struct DmaMap {
+    list: Option<PrpList>,
+    stage: Option<(usize, usize)>,
+    buf: usize,
+    len: usize
+}
+
+struct Window {
+    cmds: Vec<Cmd>,
+    maps: Vec<Option<DmaMap>>,
+    cids: Vec<u16>
+}
+
+impl Window {
+    fn new(width: usize) -> Self {
+        return Self {
+            cmds: Vec::with_capacity(width),
+            maps: Vec::with_capacity(width),
+            cids: Vec::with_capacity(width)
+        };
+    }
+
+    fn width(&self) -> usize {
+        return self.cmds.capacity().max(1);
+    }
 }
 
-pub struct Ctrl<A: Dma> {
-    mmio: usize,
+pub struct Ctrl<A: Dma, M: Mmio = usize> {
+    mmio: M,
     dstrd: u8,
+    arb: ArbMode,
+    css: CmdSet,
+    pg_sz: usize,
+    cqr: bool,
     admin: Mutex<Option<Queue<A>>>,
-    io: Mutex<BTreeMap<u16, Arc<Queue<A>>>>,
+    io: QueueMap<A>,
+    prio: QueueMap<A>,
+    own: QueueMap<A>,
+    cqs: CqMap<A>,
+    adm_cq: Mutex<Option<Arc<Cq<A>>>>,
+    irq_pending: AtomicU64,
     data: Arc<CtrlData>,
+    id: Mutex<Arc<CtrlId>>,
+    oncs: AtomicU16,
     alloc: Arc<A>,
+    pool: DmaPool<A>,
+    bounce: Option<BouncePool<A>>,
+    retry: RetryPolicy,
+    acre: AtomicBool,
     active: AtomicBool,
-    rr_cnt: AtomicU16
+    rr_cnt: AtomicU16,
+    nsq: AtomicU16,
+    ncq: AtomicU16,
+    epoch: AtomicU64,
+    clock: Arc<dyn Clock>,
+    rdy_to: u64,
+    shdn_to: u64,
+    adm_to: AtomicU64,
+    io_to: AtomicU64,
+    full_block: AtomicBool,
+    adm_sz: usize,
+    io_sz: usize,
+    feats: Mutex<BTreeMap<u8, u32>>,
+    qids: Mutex<()>,
+    rst: Mutex<()>
 }
 
-impl<A: Dma> Ctrl<A> {
-    pub fn new(mmio: usize, alloc: A) -> Result<Self> {
+impl<A: Dma, M: Mmio> Ctrl<A, M> {
+    pub fn new(mmio: M, alloc: A, cfg: &CtrlConfig) -> Result<Self> {
+        let alloc = Arc::new(alloc);
         let mut ctrl = Self {
             mmio,
             dstrd: 0,
+            arb: cfg.arb,
+            css: cfg.css,
+            pg_sz: 4096,
+            cqr: true,
             admin: Mutex::new(None),
             io: Mutex::new(BTreeMap::new()),
+            prio: Mutex::new(BTreeMap::new()),
+            own: Mutex::new(BTreeMap::new()),
+            cqs: Mutex::new(BTreeMap::new()),
+            adm_cq: Mutex::new(None),
+            irq_pending: AtomicU64::new(0),
             data: Arc::new(CtrlData {
                 serial: String::new(),
                 model: String::new(),
                 firm: String::new(),
                 mts: 0,
                 mqe: 0,
-                min_pg: 0
+                min_pg: 0,
+                sgl_align: None,
+                crdt: [0; 3]
             }),
-            alloc: Arc::new(alloc),
+            id: Mutex::new(Arc::new(unsafe { core::mem::zeroed() })),
+            oncs: AtomicU16::new(0),
+            pool: DmaPool::new(&alloc),
+            bounce: cfg.bounce.map(|keep| BouncePool::new(&alloc, keep)),
+            alloc,
+            retry: cfg.retry,
+            acre: AtomicBool::new(false),
             active: AtomicBool::new(true),
-            rr_cnt: AtomicU16::new(0)
+            rr_cnt: AtomicU16::new(0),
+            nsq: AtomicU16::new(0),
+            ncq: AtomicU16::new(0),
+            epoch: AtomicU64::new(0),
+            clock: cfg.clock.clone(),
+            rdy_to: 0,
+            shdn_to: 0,
+            adm_to: AtomicU64::new(60_000_000),
+            io_to: AtomicU64::new(30_000_000),
+            full_block: AtomicBool::new(false),
+            adm_sz: 0,
+            io_sz: 0,
+            feats: Mutex::new(BTreeMap::new()),
+            qids: Mutex::new(()),
+            rst: Mutex::new(())
         };
 
-        ctrl.init()?;
+        ctrl.init(cfg)?;
         return Ok(ctrl);
     }
 
-    fn init(&mut self) -> Result<()> {
-        let cap: u64 = unsafe { self.read(reg::CAP) };
-        self.dstrd = ((cap >> 32) & 0xF) as u8;
-
-        unsafe {
-            let cc: u32 = self.read(reg::CC);
-            if (cc & reg::CC_EN) != 0 {
-                self.write(reg::CC, 0u32);
-                while self.read::<u32>(reg::CSTS) & reg::CSTS_RDY != 0 {
-                    spin_loop();
-                }
-            }
+    fn init(&mut self, cfg: &CtrlConfig) -> Result<()> {
+        let cap = CapReg::from_raw(self.mmio.read64(reg::CAP));
+        (self.adm_sz, self.io_sz, self.pg_sz) = cfg.check(cap)?;
+        self.dstrd = cap.dstrd();
+        self.cqr = cap.cqr();
+        self.rdy_to = cap.timeout().max(1) as u64 * 500_000;
+        self.shdn_to = self.rdy_to;
+
+        let mut cc = CcReg::from_raw(self.mmio.read32(reg::CC));
+        if cc.is_enabled() {
+            self.mmio.write32(reg::CC, cc.disable().raw());
+            self.wait_csts(self.rdy_to, |csts| csts & reg::CSTS_RDY == 0)?;
         }
 
-        let mqes = ((cap & 0xFFFF) + 1) as usize;
-        let admin_size = mqes;
-
-        let admin = Queue::new(0, admin_size, self.alloc.as_ref())?;
-
-        unsafe {
-            self.write(reg::ASQ, admin.sq_phys());
-            self.write(reg::ACQ, admin.cq_phys());
-
-            let aqa = ((admin_size - 1) << 16) | (admin_size - 1);
-            self.write(reg::AQA, aqa as u32);
-
-            let cc = (4 << 20) | (6 << 16) | 1;
-            self.write(reg::CC, cc);
-
-            while self.read::<u32>(reg::CSTS) & reg::CSTS_RDY == 0 {
-                spin_loop();
-            }
-        }
+        let mqes = cap.mqes() as usize + 1;
 
+        self.pool.reserve(ADM_BUFS)?;
+        let admin = Queue::new(0, self.adm_sz, self.pg_sz, true, &self.alloc)?;
+        self.enable(&admin)?;
+        self.set_adm_cq(&admin);
         *self.admin.lock() = Some(admin);
 
-        let id_buf_size = 4096;
-        let id_buf = unsafe { self.alloc.alloc(id_buf_size) };
-        if id_buf == 0 {
-            return Err(NVMeError::OoRam);
-        }
-        unsafe { (id_buf as *mut u8).write_bytes(0, id_buf_size) };
-
-        let id_buf_phys = self.alloc.virt_to_phys(id_buf) as u64;
-        let cmd = Cmd::id_ctrl(id_buf_phys);
-        self.admin_cmd(&cmd)?;
-
-        let ctrl_id = unsafe { &*(id_buf as *const CtrlId) };
+        let ctrl_id = self.identify()?;
 
         let serial = ctrl_id.serial().to_string();
         let model = ctrl_id.model().to_string();
         let firm = ctrl_id.firm().to_string();
 
-        let mps_bytes = ((cap >> 48) & 0xF) as usize;
-        let min_pg = 1 << (12 + mps_bytes);
+        let min_pg = 4096 << cap.mpsmin();
 
         let mts = ctrl_id.max_xfer(min_pg).unwrap_or(usize::MAX);
 
+        let rtd3e = ctrl_id.rtd3e;
+        if rtd3e != 0 {
+            self.shdn_to = rtd3e as u64;
+        }
+
         self.data = Arc::new(CtrlData {
             serial,
             model,
             firm,
             mts,
-            mqe: mqes as u16,
-            min_pg
+            mqe: mqes.min(u16::MAX as usize) as u16,
+            min_pg,
+            sgl_align: ctrl_id.sgl_align(),
+            crdt: [ctrl_id.crdt1, ctrl_id.crdt2, ctrl_id.crdt3]
         });
 
-        unsafe { self.alloc.free(id_buf, id_buf_size) };
+        if self.retry.enabled() {
+            match self.en_acre() {
+                Ok(()) | Err(NVMeError::CmdFail(_)) => {}
+                Err(e) => return Err(e)
+            }
+        }
+        if self.css != CmdSet::AdminOnly {
+            self.set_qs_n(u16::MAX, u16::MAX)?;
+            self.set_ioq_cnt(cfg.io_cnt)?;
+        }
+        if cfg.async_ev {
+            self.en_async_ev()?;
+        }
+        return Ok(());
+    }
+
+    fn enable(&self, admin: &Queue<A>) -> Result<()> {
+        self.mmio.write64(reg::ASQ, admin.sq_phys());
+        self.mmio.write64(reg::ACQ, admin.cq_phys());
+
+        let aqa = ((self.adm_sz - 1) << 16) | (self.adm_sz - 1);
+        self.mmio.write32(reg::AQA, aqa as u32);
+
+        let ams = match self.arb {
+            ArbMode::RoundRobin => reg::CC_AMS_RR,
+            ArbMode::WeightedRoundRobin => reg::CC_AMS_WRR
+        };
+        let css = match self.css {
+            CmdSet::Nvm => reg::CC_CSS_NVM,
+            CmdSet::AllIo => reg::CC_CSS_IOCS,
+            CmdSet::AdminOnly => reg::CC_CSS_NOIO
+        };
+
+        let mut cc = CcReg::new();
+        cc.set_mps((self.pg_sz >> 12).trailing_zeros() as u8)
+          .set_iosqes(6)
+          .set_iocqes(4)
+          .enable();
+        self.mmio.write32(reg::CC, cc.raw() | css | ams);
+
+        return self.wait_csts(self.rdy_to, |csts| csts & reg::CSTS_RDY != 0);
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        let _rst = self.rst.lock();
+        return self.reset_locked();
+    }
 
-        let io_size = mqes.min(256);
-        self.new_ioq(io_size)?;
+    fn recover(&self) {
+        if let Some(_rst) = self.rst.try_lock()
+            && CstsReg::from_raw(self.mmio.read32(reg::CSTS)).is_fatal()
+        {
+            let _ = self.reset_locked();
+        }
+    }
+
+    fn reset_locked(&self) -> Result<()> {
+        for map in [&self.io, &self.prio, &self.own] {
+            map.lock().values().for_each(|queue| queue.kill());
+        }
+
+        let mut cc = CcReg::from_raw(self.mmio.read32(reg::CC));
+        self.mmio.write32(reg::CC, cc.disable().raw());
+        self.wait_csts(self.rdy_to, |csts| csts & reg::CSTS_RDY == 0)?;
+
+        let mut admin = self.admin.lock();
+        *admin = None;
+        let queue = Queue::new(0, self.adm_sz, self.pg_sz, true, &self.alloc)?;
+        self.enable(&queue)?;
+        self.set_adm_cq(&queue);
+        *admin = Some(queue);
+        drop(admin);
+
+        let feats = self.feats.lock().clone();
+        for (fid, value) in feats {
+            self.adm_cmd_res(&Cmd::set_feat(fid, value))?;
+        }
+        if self.acre() {
+            self.en_acre()?;
+        }
+
+        let cqs = self.cqs()
+            .iter()
+            .map(|(&cqid, (cq, solo))| (cqid, cq.size(), cq.vector(), *solo))
+            .collect::<Vec<(u16, usize, Option<u16>, bool)>>();
+
+        for (cqid, size, iv, solo) in cqs {
+            let cq = self.make_cq(cqid, size, iv)?;
+            self.cqs().insert(cqid, (cq, solo));
+        }
+
+        self.recreate(&self.io)?;
+        self.recreate(&self.prio)?;
+        self.recreate(&self.own)?;
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        return Ok(());
+    }
+
+    fn recreate(&self, map: &QueueMap<A>) -> Result<()> {
+        let ioqs = map.lock()
+            .iter()
+            .map(|(&qid, q)| (qid, q.depth(), q.cqid(), q.prio()))
+            .collect::<Vec<(u16, usize, u16, QPrio)>>();
+
+        for (qid, size, cqid, prio) in ioqs {
+            let cq = self.cqs().get(&cqid).map(|(cq, _)| cq.clone()).ok_or(NVMeError::InvQp)?;
+            let queue = self.make_sq(qid, size, &cq, prio)?;
+            map.lock().insert(qid, Arc::new(queue));
+        }
         return Ok(());
     }
 
-    unsafe fn read<T: Copy>(&self, offset: usize) -> T {
-        return unsafe { ((self.mmio + offset) as *const T).read_volatile() };
+    fn wait_csts(&self, to_us: u64, done: impl Fn(u32) -> bool) -> Result<()> {
+        let dl = Deadline::new(self.clock.as_ref(), to_us);
+        loop {
+            if done(self.mmio.read32(reg::CSTS)) {
+                return Ok(());
+            }
+            if dl.expired() {
+                return Err(NVMeError::Timeout);
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn set_admin_timeout(&self, us: u64) {
+        self.adm_to.store(us, Ordering::Relaxed);
+    }
+
+    pub fn set_io_timeout(&self, us: u64) {
+        self.io_to.store(us, Ordering::Relaxed);
     }
 
-    unsafe fn write<T: Copy>(&self, offset: usize, val: T) {
-        return unsafe { ((self.mmio + offset) as *mut T).write_volatile(val); };
+    pub fn set_block_on_full(&self, block: bool) {
+        self.full_block.store(block, Ordering::Relaxed);
     }
 
     pub fn admin_cmd(&self, cmd: &Cmd) -> Result<()> {
-        if let Some(ref admin) = *self.admin.lock() {
-            admin.submit(cmd, self.mmio, self.dstrd)?;
-            return Ok(());
-        }
-        return Err(NVMeError::InvQp);
+        self.adm_cmd_res(cmd)?;
+        return Ok(());
     }
 
     pub fn io_cmd(&self, cmd: &Cmd) -> Result<()> {
+        return self.io_cmd_prio(QPrio::Medium, cmd);
+    }
+
+    pub fn io_cmd_prio(&self, prio: QPrio, cmd: &Cmd) -> Result<()> {
+        let queue = self.pick_prio(prio)?;
+        let res = self.io_once(&queue, cmd);
+        return self.retry(CmdClass::of_io(cmd), res, || self.io_once(&queue, cmd));
+    }
+
+    pub fn io_batch(&self, cmds: &[Cmd]) -> core::result::Result<(), (usize, NVMeError)> {
+        let queue = self.pick_ioq().map_err(|e| (0, e))?;
+        let mut cids = Vec::with_capacity(cmds.len().min(self.io_depth()));
+        return self.batch_on(&queue, cmds, &mut cids);
+    }
+
+    pub fn io_chunks(
+        &self,
+        n: usize,
+        copy: bool,
+        mk: impl FnMut(&DmaPool<A>, usize) -> Result<(Cmd, Option<DmaMap>)>
+    ) -> core::result::Result<(), (usize, NVMeError)> {
+        let queue = self.pick_ioq().map_err(|e| (0, e))?;
+        let mut win = Window::new(n.min(self.io_depth()));
+        return self.chunks_on(&queue, &self.pool, &mut win, n, copy, mk);
+    }
+
+    fn chunks_on(
+        &self,
+        queue: &Queue<A>,
+        pool: &DmaPool<A>,
+        win: &mut Window,
+        n: usize,
+        copy: bool,
+        mut mk: impl FnMut(&DmaPool<A>, usize) -> Result<(Cmd, Option<DmaMap>)>
+    ) -> core::result::Result<(), (usize, NVMeError)> {
+        let width = win.width();
+
+        let mut i = 0;
+        while i < n {
+            let start = i;
+            let mut failed = None;
+            win.cmds.clear();
+
+            while win.cmds.len() < width && i < n {
+                match mk(pool, i) {
+                    Ok((cmd, map)) => {
+                        win.cmds.push(cmd);
+                        win.maps.push(map);
+                    }
+                    Err(e) => {
+                        failed = Some((i, e));
+                        break;
+                    }
+                }
+                i += 1;
+            }
+
+            let res = match failed {
+                Some(e) => Err(e),
+                None => self.batch_on(queue, &win.cmds, &mut win.cids).map_err(|(k, e)| (start + k, e))
+            };
+
+            for map in win.maps.drain(..).flatten() {
+                self.unmap_data_in(pool, map, copy && res.is_ok());
+            }
+            res?;
+        }
+
+        return Ok(());
+    }
+
+    fn batch_on(
+        &self,
+        queue: &Queue<A>,
+        cmds: &[Cmd],
+        cids: &mut Vec<u16>
+    ) -> core::result::Result<(), (usize, NVMeError)> {
+        let to_us = self.io_to.load(Ordering::Relaxed);
+
+        let mut done = 0;
+        while done < cmds.len() {
+            let dl = Deadline::new(self.clock.as_ref(), to_us);
+            loop {
+                let n = queue.space().min(cmds.len() - done);
+                if n > 0 {
+                    match queue.issue_batch_into(&cmds[done..done + n], cids, &self.mmio, self.dstrd) {
+                        Ok(()) => break,
+                        Err(NVMeError::FullQp) => {}
+                        Err(e) => return Err((done, e))
+                    }
+                }
+                queue.reap(&self.mmio, self.dstrd);
+                if dl.expired() {
+                    return Err((done, NVMeError::Timeout));
+                }
+                spin_loop();
+            }
+
+            let mut failed = None;
+            for (i, cid) in cids.iter().enumerate() {
+                let cmd = &cmds[done + i];
+                let res = self.io_wait(queue, *cid);
+                let res = self.retry(CmdClass::of_io(cmd), res, || self.io_once(queue, cmd));
+                if let Err(e) = res && failed.is_none() {
+                    failed = Some((done + i, e));
+                }
+            }
+            if let Some(failed) = failed {
+                return Err(failed);
+            }
+            done += cids.len();
+        }
+
+        return Ok(());
+    }
+
+    fn io_once(&self, queue: &Queue<A>, cmd: &Cmd) -> Result<()> {
+        return self.io_issue(queue, cmd).and_then(|cid| self.io_wait(queue, cid));
+    }
+
+    fn retry<T>(&self, class: CmdClass, mut res: Result<T>, mut again: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut left = self.retry.retries(class);
+        while left > 0 && let Err(NVMeError::CmdFail(st)) = res && st.is_transient() {
+            self.retry_delay(st.crd);
+            left -= 1;
+            res = again();
+        }
+        return res;
+    }
+
+    fn retry_delay(&self, crd: u8) {
+        let ms = match crd {
+            1..=3 => self.data.crdt[crd as usize - 1] as u64 * 100,
+            _ => 0
+        };
+
+        let dl = Deadline::new(self.clock.as_ref(), ms * 1000);
+        while !dl.expired() {
+            spin_loop();
+        }
+    }
+
+    fn io_issue(&self, queue: &Queue<A>, cmd: &Cmd) -> Result<u16> {
+        if !self.full_block.load(Ordering::Relaxed) {
+            return queue.issue(cmd, &self.mmio, self.dstrd);
+        }
+
+        let to_us = self.io_to.load(Ordering::Relaxed);
+        return queue.issue_wait(cmd, &self.mmio, self.dstrd, self.clock.as_ref(), to_us);
+    }
+
+    fn pick_ioq(&self) -> Result<Arc<Queue<A>>> {
         let io = self.io.lock();
         if io.is_empty() {
             return Err(NVMeError::InvQp);
         }
 
         let cnt = self.rr_cnt.fetch_add(1, Ordering::Relaxed) as usize;
-        let keys = io.keys().cloned().collect::<Vec<u16>>();
-        let qid = keys[cnt % keys.len()];
+        return io.values().nth(cnt % io.len()).cloned().ok_or(NVMeError::InvQp);
+    }
 
-        let queue = io.get(&qid).ok_or(NVMeError::InvQp)?.clone();
-        drop(io);
-        queue.submit(cmd, self.mmio, self.dstrd)?;
+    fn pick_prio(&self, prio: QPrio) -> Result<Arc<Queue<A>>> {
+        if prio == QPrio::Medium {
+            return self.pick_ioq();
+        }
+
+        let queues = self.prio.lock();
+        let n = queues.values().filter(|q| q.prio() == prio).count();
+        if n == 0 {
+            return Err(NVMeError::InvQp);
+        }
+
+        let cnt = self.rr_cnt.fetch_add(1, Ordering::Relaxed) as usize;
+        return queues.values().filter(|q| q.prio() == prio).nth(cnt % n).cloned().ok_or(NVMeError::InvQp);
+    }
+
+    fn ioq_map(&self, prio: QPrio) -> &QueueMap<A> {
+        return match prio {
+            QPrio::Medium => &self.io,
+            _ => &self.prio
+        };
+    }
+
+    pub fn ioq_cnt(&self) -> usize {
+        return self.io.lock().len();
+    }
+
+    pub fn io_depth(&self) -> usize {
+        return self.io_sz - 1;
+    }
+
+    pub fn io_queue(&self, qid: u16) -> Option<Arc<Queue<A>>> {
+        return self.io.lock().get(&qid).or(self.prio.lock().get(&qid)).cloned();
+    }
+
+    pub fn io_qids(&self) -> Vec<u16> {
+        return self.io.lock().keys().cloned().collect();
+    }
+
+    pub fn prio_qids(&self, prio: QPrio) -> Vec<u16> {
+        return self.ioq_map(prio).lock()
+            .iter()
+            .filter(|(_, q)| q.prio() == prio)
+            .map(|(&qid, _)| qid)
+            .collect();
+    }
+
+    pub fn on_interrupt(&self, vector: u16) -> usize {
+        self.irq_pending.fetch_or(irq_bit(vector), Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        return self.irq_drain();
+    }
+
+    fn irq_drain(&self) -> usize {
+        let mut n = 0;
+        while self.irq_pending.load(Ordering::SeqCst) != 0 {
+            let Some(cqs) = self.cqs.try_lock() else {
+                return n;
+            };
+            let pending = self.irq_pending.swap(0, Ordering::SeqCst);
+            let mut hit = cqs.values()
+                .filter(|(cq, _)| cq.vector().is_some_and(|v| pending & irq_bit(v) != 0))
+                .map(|(cq, _)| cq.clone())
+                .collect::<Vec<Arc<Cq<A>>>>();
+            if pending & irq_bit(0) != 0 && let Some(cq) = self.adm_cq.lock().clone() {
+                hit.push(cq);
+            }
+            drop(cqs);
+
+            for cq in hit {
+                n += cq.reap(&self.mmio, self.dstrd);
+            }
+        }
+        return n;
+    }
+
+    fn cqs(&self) -> CqsGuard<'_, A, M> {
+        return CqsGuard { ctrl: self, map: Some(self.cqs.lock()) };
+    }
+
+    fn set_adm_cq(&self, admin: &Queue<A>) {
+        let _cqs = self.cqs();
+        *self.adm_cq.lock() = Some(admin.cq().clone());
+    }
+
+    pub fn int_mask(&self, vectors: u32) {
+        self.mmio.write32(reg::INTMS, vectors);
+    }
+
+    pub fn int_unmask(&self, vectors: u32) {
+        self.mmio.write32(reg::INTMC, vectors);
+    }
+
+    pub fn mmio(&self) -> &M {
+        return &self.mmio;
+    }
+
+    pub fn version(&self) -> u32 {
+        return self.mmio.read32(reg::VS);
+    }
+
+    pub fn dstrd(&self) -> u8 {
+        return self.dstrd;
+    }
+
+    fn io_wait(&self, queue: &Queue<A>, cid: u16) -> Result<()> {
+        self.io_wait_raw(queue, cid)?.into_result()?;
         return Ok(());
     }
 
-    pub fn new_ioq(&self, size: usize) -> Result<()> {
-        let mut qid = 0;
-        for i in 1..=self.data.mqe {
-            if !self.io.lock().contains_key(&i) {
-                qid = i;
-                break;
+    fn io_wait_raw(&self, queue: &Queue<A>, cid: u16) -> Result<Cqe> {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let dl = Deadline::new(self.clock.as_ref(), self.io_to.load(Ordering::Relaxed));
+        let res = match queue.poll_cid(cid, &self.mmio, self.dstrd, &dl) {
+            Err(NVMeError::Timeout) => match self.abort(queue.qid(), cid) {
+                Ok(true) => Err(NVMeError::Aborted),
+                _ => queue.try_take(cid, &self.mmio, self.dstrd).ok_or(NVMeError::Timeout)
+            },
+            res => res
+        };
+
+        let res = queue.finish(cid, res);
+        match res {
+            Err(NVMeError::Timeout) => {
+                let _rst = self.rst.lock();
+                if self.epoch.load(Ordering::Acquire) == epoch {
+                    let _ = self.reset_locked();
+                }
             }
+            Err(NVMeError::Reset) => self.recover(),
+            _ => {}
         }
-        if qid == 0 || size == 0 {
+        return res;
+    }
+
+    pub fn abort(&self, sqid: u16, cid: u16) -> Result<bool> {
+        let cmd = Cmd::abort(sqid, cid);
+        let cqe = self.adm_cmd_res(&cmd)?;
+        return Ok((cqe.dw0 & 1) == 0);
+    }
+
+    pub fn new_ioq(&self, size: usize, prio: QPrio) -> Result<()> {
+        return self.new_pooled_ioq(size, None, prio);
+    }
+
+    pub fn new_ioq_irq(&self, size: usize, vector: u16, prio: QPrio) -> Result<()> {
+        return self.new_pooled_ioq(size, Some(vector), prio);
+    }
+
+    fn new_pooled_ioq(&self, size: usize, iv: Option<u16>, prio: QPrio) -> Result<()> {
+        self.check_depth(size)?;
+        self.pool.reserve(size)?;
+        if let Err(e) = self.add_ioq_to(self.ioq_map(prio), size, iv, prio) {
+            self.pool.unreserve(size);
+            return Err(e);
+        }
+        return Ok(());
+    }
+
+    pub fn new_qpair(self: &Arc<Self>, size: usize, prio: QPrio) -> Result<IoQueuePair<A, M>> {
+        self.check_depth(size)?;
+        let prp = DmaPool::new(&self.alloc);
+        prp.reserve(size)?;
+
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let queue = self.add_ioq_to(&self.own, size, None, prio)?;
+        let win = Window::new(queue.depth() - 1);
+        return Ok(IoQueuePair { ctrl: self.clone(), queue, prp: Arc::new(prp), epoch, win });
+    }
+
+    fn add_ioq_to(
+        &self,
+        map: &QueueMap<A>,
+        size: usize,
+        iv: Option<u16>,
+        prio: QPrio
+    ) -> Result<Arc<Queue<A>>> {
+        let _qids = self.qids.lock();
+        let qid = (1..=self.nsq().min(self.ncq()))
+            .find(|&i| self.sqid_free(i) && !self.cqs().contains_key(&i))
+            .ok_or(NVMeError::FullQp)?;
+        if size == 0 {
             return Err(NVMeError::FullQp);
         }
 
-        let io = Queue::new(qid, size, self.alloc.as_ref())?;
+        let cq = self.make_cq(qid, size, iv)?;
+        let io = match self.make_sq(qid, size, &cq, prio) {
+            Ok(io) => Arc::new(io),
+            Err(e) => {
+                let _ = self.admin_cmd(&Cmd::cq_del(qid));
+                return Err(e);
+            }
+        };
 
-        let cmd = Cmd::cq_create(qid, size as u16, io.cq_phys());
-        self.admin_cmd(&cmd)?;
+        self.cqs().insert(qid, (cq, false));
+        map.lock().insert(qid, io.clone());
+        return Ok(io);
+    }
 
-        let cmd = Cmd::sq_create(qid, size as u16, qid, io.sq_phys());
-        self.admin_cmd(&cmd)?;
+    pub fn create_cq(&self, size: usize, vector: Option<u16>) -> Result<u16> {
+        self.check_depth(size)?;
+        let _qids = self.qids.lock();
+        let cqid = (1..=self.ncq())
+            .find(|i| !self.cqs().contains_key(i))
+            .ok_or(NVMeError::FullQp)?;
+        if size == 0 {
+            return Err(NVMeError::FullQp);
+        }
+
+        let cq = self.make_cq(cqid, size, vector)?;
+        self.cqs().insert(cqid, (cq, true));
+        return Ok(cqid);
+    }
+
+    pub fn create_sq(&self, cqid: u16, size: usize, prio: QPrio) -> Result<u16> {
+        self.check_depth(size)?;
+        let _qids = self.qids.lock();
+        let cq = match self.cqs().get(&cqid) {
+            Some((cq, true)) => cq.clone(),
+            _ => return Err(NVMeError::InvQp)
+        };
 
-        self.io.lock().insert(qid, Arc::new(io));
+        let sqid = (1..=self.nsq())
+            .find(|&i| self.sqid_free(i))
+            .ok_or(NVMeError::FullQp)?;
+        if size == 0 {
+            return Err(NVMeError::FullQp);
+        }
+
+        self.pool.reserve(size)?;
+        let io = match self.make_sq(sqid, size, &cq, prio) {
+            Ok(io) => io,
+            Err(e) => {
+                self.pool.unreserve(size);
+                return Err(e);
+            }
+        };
+        self.ioq_map(prio).lock().insert(sqid, Arc::new(io));
+        return Ok(sqid);
+    }
+
+    pub fn rm_cq(&self, cqid: u16) -> Result<()> {
+        let _qids = self.qids.lock();
+        let mut cqs = self.cqs();
+        let cq = match cqs.get(&cqid) {
+            Some((cq, true)) if cq.sq_cnt() == 0 => cqs.remove(&cqid),
+            _ => return Err(NVMeError::InvQp)
+        };
+        drop(cqs);
+
+        let res = self.admin_cmd(&Cmd::cq_del(cqid));
+        if let Err(e) = &res && !matches!(e, NVMeError::Reset) && let Some(cq) = cq {
+            self.cqs().insert(cqid, cq);
+        }
+        return res;
+    }
+
+    fn sqid_free(&self, qid: u16) -> bool {
+        return [&self.io, &self.prio, &self.own].iter().all(|map| !map.lock().contains_key(&qid));
+    }
+
+    fn check_depth(&self, size: usize) -> Result<()> {
+        if !(2..=self.data.mqe as usize).contains(&size) {
+            return Err(NVMeError::InvCfg);
+        }
         return Ok(());
     }
 
+    fn make_cq(&self, cqid: u16, size: usize, iv: Option<u16>) -> Result<Arc<Cq<A>>> {
+        let cq = Cq::new(cqid, size, self.pg_sz, self.cqr, &self.alloc)?.with_vector(iv);
+
+        let cmd = Cmd::cq_create(cqid, size as u16, cq.phys(), cq.contig(), iv);
+        self.admin_cmd(&cmd)?;
+        return Ok(Arc::new(cq));
+    }
+
+    fn make_sq(&self, sqid: u16, size: usize, cq: &Arc<Cq<A>>, prio: QPrio) -> Result<Queue<A>> {
+        let io = Queue::with_cq(sqid, size, self.pg_sz, self.cqr, cq, &self.alloc)?.with_prio(prio);
+
+        let cmd = Cmd::sq_create(sqid, size as u16, cq.qid(), io.sq_phys(), io.sq_contig(), prio);
+        self.admin_cmd(&cmd)?;
+        return Ok(io);
+    }
+
     pub fn rm_ioq(&self, qid: u16) -> Result<()> {
+        let map = match self.prio.lock().contains_key(&qid) {
+            true => &self.prio,
+            false => &self.io
+        };
+        return self.rm_ioq_from(map, qid);
+    }
+
+    fn rm_ioq_from(&self, map: &QueueMap<A>, qid: u16) -> Result<()> {
         if qid == 0 {
             return Err(NVMeError::InvQp);
         }
 
-        let mut io = self.io.lock();
+        let mut io = map.lock();
         if !io.contains_key(&qid) {
             return Err(NVMeError::InvQp);
         }
 
+        let dl = Deadline::new(self.clock.as_ref(), self.io_to.load(Ordering::Relaxed));
         loop {
             if io.get(&qid).map(|q| q.is_idle()).unwrap_or(true) {
                 break;
             }
             drop(io);
+            if dl.expired() {
+                return Err(NVMeError::Timeout);
+            }
             spin_loop();
-            io = self.io.lock();
+            io = map.lock();
         }
 
+        let cqid = io.get(&qid).map(|q| q.cqid()).unwrap_or(qid);
         drop(io);
 
         let cmd = Cmd::sq_del(qid);
         self.admin_cmd(&cmd)?;
+        if let Some(queue) = map.lock().remove(&qid) && !ptr::eq(map, &self.own) {
+            self.pool.unreserve(queue.depth());
+        }
 
-        let cmd = Cmd::cq_del(qid);
-        self.admin_cmd(&cmd)?;
-
-        self.io.lock().remove(&qid);
+        let paired = matches!(self.cqs().get(&cqid), Some((_, false)));
+        if paired {
+            let cmd = Cmd::cq_del(cqid);
+            self.admin_cmd(&cmd)?;
+            self.cqs().remove(&cqid);
+        }
         return Ok(());
     }
 
     pub fn shutdown(&self) -> Result<()> {
         self.active.store(false, Ordering::SeqCst);
 
+        let dl = Deadline::new(self.clock.as_ref(), self.io_to.load(Ordering::Relaxed));
         loop {
-            let io = self.io.lock();
-            let all_idle = io.values().all(|q| q.is_idle());
-            drop(io);
+            let all_idle = [&self.io, &self.prio, &self.own]
+                .iter()
+                .all(|map| map.lock().values().all(|q| q.is_idle()));
 
             if all_idle {
                 break;
             }
+            if dl.expired() {
+                self.active.store(true, Ordering::SeqCst);
+                return Err(NVMeError::Timeout);
+            }
             spin_loop();
         }
 
+        let dl = Deadline::new(self.clock.as_ref(), self.adm_to.load(Ordering::Relaxed));
         loop {
             let admin = self.admin.lock();
             if admin.as_ref().map(|q| q.is_idle()).unwrap_or(true) {
                 break;
             }
             drop(admin);
+            if dl.expired() {
+                self.active.store(true, Ordering::SeqCst);
+                return Err(NVMeError::Timeout);
+            }
             spin_loop();
         }
 
-        unsafe {
-            let mut cc: u32 = self.read(reg::CC);
-            cc = (cc & !(0x3 << 14)) | reg::CC_SHN_NORMAL;
-            self.write(reg::CC, cc);
-
-            loop {
-                let csts: u32 = self.read(reg::CSTS);
-                let shst = (csts >> 2) & 0x3;
-                if shst == 2 {
-                    break;
-                }
-                spin_loop();
-            }
+        let mut cc = self.mmio.read32(reg::CC);
+        cc = (cc & !(0x3 << 14)) | reg::CC_SHN_NORMAL;
+        self.mmio.write32(reg::CC, cc);
 
-            cc &= !reg::CC_EN;
-            self.write(reg::CC, cc);
+        let shdn = self.wait_csts(self.shdn_to, |csts| {
+            csts & (0x3 << 2) == reg::CSTS_SHST_COMPLETE
+        });
 
-            loop {
-                let csts: u32 = self.read(reg::CSTS);
-                if (csts & reg::CSTS_RDY) == 0 {
-                    break;
-                }
-                spin_loop();
-            }
-        }
+        cc &= !reg::CC_EN;
+        self.mmio.write32(reg::CC, cc);
 
-        return Ok(());
+        self.wait_csts(self.rdy_to, |csts| csts & reg::CSTS_RDY == 0)?;
+        return shdn;
     }
 
     pub fn resume(&self) -> Result<()> {
-        unsafe {
-            let mut cc: u32 = self.read(reg::CC);
-            cc |= reg::CC_EN;
-            self.write(reg::CC, cc);
+        let mut cc = CcReg::from_raw(self.mmio.read32(reg::CC));
+        self.mmio.write32(reg::CC, cc.enable().raw());
 
-            loop {
-                let csts: u32 = self.read(reg::CSTS);
-                if (csts & reg::CSTS_RDY) != 0 {
-                    break;
-                }
-                spin_loop();
-            }
-        }
+        self.wait_csts(self.rdy_to, |csts| csts & reg::CSTS_RDY != 0)?;
 
         self.active.store(true, Ordering::SeqCst);
         return Ok(());
@@ -283,12 +1122,71 @@ impl<A: Dma> Ctrl<A> {
         return &self.alloc;
     }
 
+    pub fn pool(&self) -> &DmaPool<A> {
+        return &self.pool;
+    }
+
+    pub fn bounce(&self) -> Option<&BouncePool<A>> {
+        return self.bounce.as_ref();
+    }
+
+    pub fn bounce_stats(&self) -> Option<BounceStats> {
+        return self.bounce.as_ref().map(|pool| pool.stats());
+    }
+
     pub fn data(&self) -> &CtrlData {
         return &self.data;
     }
 
+    pub fn identify(&self) -> Result<CtrlId> {
+        let buf_sz = size_of::<CtrlId>();
+        let buf = unsafe { self.pool.alloc(buf_sz) };
+        if buf == 0 {
+            return Err(NVMeError::OoRam);
+        }
+        unsafe { (buf as *mut u8).write_bytes(0, buf_sz) };
+
+        let cmd = Cmd::id_ctrl(self.pool.virt_to_phys(buf) as u64);
+        let res = self.admin_cmd(&cmd);
+        let id = unsafe { (buf as *const CtrlId).read_unaligned() };
+        unsafe { self.pool.free(buf, buf_sz) };
+        res?;
+
+        self.oncs.store(id.oncs, Ordering::Relaxed);
+        *self.id.lock() = Arc::new(id);
+        return Ok(id);
+    }
+
+    pub fn ctrl_id(&self) -> Arc<CtrlId> {
+        return self.id.lock().clone();
+    }
+
+    pub fn supports_compare(&self) -> bool {
+        return self.oncs.load(Ordering::Relaxed) & ONCS_COMPARE != 0;
+    }
+
+    pub fn supports_dsm(&self) -> bool {
+        return self.oncs.load(Ordering::Relaxed) & ONCS_DSM != 0;
+    }
+
+    pub fn supports_write_zeroes(&self) -> bool {
+        return self.oncs.load(Ordering::Relaxed) & ONCS_WR_ZERO != 0;
+    }
+
+    pub fn supports_verify(&self) -> bool {
+        return self.oncs.load(Ordering::Relaxed) & ONCS_VERIFY != 0;
+    }
+
+    pub fn supports_ns_mgmt(&self) -> bool {
+        return self.ctrl_id().supports_ns_mgmt();
+    }
+
+    pub fn supports_sanitize(&self, kind: SanitizeKind) -> bool {
+        return self.ctrl_id().supports_sanitize(kind);
+    }
+
     pub fn reg_nss(&self) -> Result<Vec<u32>> {
-        let buf = unsafe { self.alloc.alloc(4096) };
+        let buf = unsafe { self.pool.alloc(4096) };
         if buf == 0 {
             return Err(NVMeError::OoRam);
         }
@@ -297,9 +1195,12 @@ impl<A: Dma> Ctrl<A> {
             (buf as *mut u8).write_bytes(0, 4096);
         }
 
-        let buf_phys = self.alloc.virt_to_phys(buf) as u64;
+        let buf_phys = self.pool.virt_to_phys(buf) as u64;
         let cmd = Cmd::id_nss(buf_phys);
-        self.admin_cmd(&cmd)?;
+        if let Err(e) = self.admin_cmd(&cmd) {
+            unsafe { self.pool.free(buf, 4096); }
+            return Err(e);
+        }
 
         let mut ns_list = Vec::new();
         unsafe {
@@ -313,16 +1214,16 @@ impl<A: Dma> Ctrl<A> {
             }
         }
 
-        unsafe { self.alloc.free(buf, 4096); }
+        unsafe { self.pool.free(buf, 4096); }
         return Ok(ns_list);
     }
 
     pub fn log_page(&self, lid: u8, buf: &mut [u8]) -> Result<()> {
-        if buf.len() < 4 || buf.len() % 4 != 0 {
+        if buf.len() < 4 || !buf.len().is_multiple_of(4) {
             return Err(NVMeError::InvBuf);
         }
 
-        let buf_phys = self.alloc.virt_to_phys(buf.as_ptr() as usize) as u64;
+        let buf_phys = self.pool.virt_to_phys(buf.as_ptr() as usize) as u64;
         let numdl = ((buf.len() / 4) - 1) as u16;
 
         let cmd = Cmd::get_log(lid, numdl, buf_phys, 0);
@@ -331,7 +1232,7 @@ impl<A: Dma> Ctrl<A> {
     }
 
     pub fn smart_log(&self) -> Result<crate::id::LogSmart> {
-        let buf = unsafe { self.alloc.alloc(512) };
+        let buf = unsafe { self.pool.alloc(512) };
         if buf == 0 {
             return Err(NVMeError::OoRam);
         }
@@ -340,19 +1241,18 @@ impl<A: Dma> Ctrl<A> {
             (buf as *mut u8).write_bytes(0, 512);
         }
 
-        let buf_phys = self.alloc.virt_to_phys(buf) as u64;
+        let buf_phys = self.pool.virt_to_phys(buf) as u64;
         let cmd = Cmd::get_log(crate::id::LOG_SMART, 127, buf_phys, 0);
-        self.admin_cmd(&cmd)?;
-
-        let smart = unsafe { (buf as *const LogSmart).read_volatile() };
-        unsafe { self.alloc.free(buf, 512); }
+        let res = self.admin_cmd(&cmd)
+            .map(|_| unsafe { (buf as *const LogSmart).read_volatile() });
+        unsafe { self.pool.free(buf, 512); }
 
-        return Ok(smart);
+        return res;
     }
 
     pub fn error_log(&self, entries: usize) -> Result<Vec<LogErr>> {
         let buf_size = entries * size_of::<LogErr>();
-        let buf = unsafe { self.alloc.alloc(buf_size) };
+        let buf = unsafe { self.pool.alloc(buf_size) };
         if buf == 0 {
             return Err(NVMeError::OoRam);
         }
@@ -361,10 +1261,13 @@ impl<A: Dma> Ctrl<A> {
             (buf as *mut u8).write_bytes(0, buf_size);
         }
 
-        let buf_phys = self.alloc.virt_to_phys(buf) as u64;
+        let buf_phys = self.pool.virt_to_phys(buf) as u64;
         let numdl = ((buf_size / 4) - 1) as u16;
         let cmd = Cmd::get_log(crate::id::LOG_ERR, numdl, buf_phys, 0);
-        self.admin_cmd(&cmd)?;
+        if let Err(e) = self.admin_cmd(&cmd) {
+            unsafe { self.pool.free(buf, buf_size); }
+            return Err(e);
+        }
 
         let mut errors = Vec::new();
         unsafe {
@@ -376,7 +1279,7 @@ impl<A: Dma> Ctrl<A> {
                 }
                 errors.push(entry);
             }
-            self.alloc.free(buf, buf_size);
+            self.pool.free(buf, buf_size);
         }
 
         return Ok(errors);
@@ -385,6 +1288,7 @@ impl<A: Dma> Ctrl<A> {
     pub fn set_feat(&self, fid: u8, value: u32) -> Result<()> {
         let cmd = Cmd::set_feat(fid, value);
         self.admin_cmd(&cmd)?;
+        self.feats.lock().insert(fid, value);
         return Ok(());
     }
 
@@ -395,39 +1299,258 @@ impl<A: Dma> Ctrl<A> {
     }
 
     fn adm_cmd_res(&self, cmd: &Cmd) -> Result<Cqe> {
-        if let Some(ref admin) = *self.admin.lock() {
-            return admin.submit(cmd, self.mmio, self.dstrd);
+        let res = self.adm_once(cmd);
+        if matches!(cmd.opc, 0x00 | 0x01 | 0x04 | 0x05 | 0x08 | 0x09) {
+            return res;
+        }
+        return self.retry(CmdClass::Admin, res, || self.adm_once(cmd));
+    }
+
+    fn adm_once(&self, cmd: &Cmd) -> Result<Cqe> {
+        return self.adm_raw(cmd)?.into_result();
+    }
+
+    fn adm_raw(&self, cmd: &Cmd) -> Result<Cqe> {
+        let res = match *self.admin.lock() {
+            Some(ref admin) => {
+                let to_us = self.adm_to.load(Ordering::Relaxed);
+                admin.submit_raw(cmd, &self.mmio, self.dstrd, self.clock.as_ref(), to_us)
+            }
+            None => return Err(NVMeError::InvQp)
+        };
+
+        if let Err(NVMeError::Reset) = res {
+            self.recover();
+        }
+        return res;
+    }
+
+    pub fn admin_passthru(&self, pt: &mut Passthru) -> Result<Cqe> {
+        let (cmd, data, meta) = self.pt_map(&self.pool, pt, true)?;
+        let res = self.adm_raw(&cmd);
+        self.pt_unmap(&self.pool, data, meta, pt.dir() == Some(DataDir::FromDev) && res.is_ok());
+        return res;
+    }
+
+    pub fn io_passthru(&self, pt: &mut Passthru) -> Result<Cqe> {
+        let queue = self.pick_ioq()?;
+        let (cmd, data, meta) = self.pt_map(&self.pool, pt, false)?;
+        let res = self.io_issue(&queue, &cmd).and_then(|cid| self.io_wait_raw(&queue, cid));
+        self.pt_unmap(&self.pool, data, meta, pt.dir() == Some(DataDir::FromDev) && res.is_ok());
+        return res;
+    }
+
+    pub fn io_fused(&self, first: &mut Passthru, second: &mut Passthru) -> Result<(Cqe, Cqe)> {
+        let queue = self.pick_ioq()?;
+        let (c1, d1, m1) = self.pt_map(&self.pool, first, false)?;
+        let (c2, d2, m2) = match self.pt_map(&self.pool, second, false) {
+            Ok(map) => map,
+            Err(e) => {
+                self.pt_unmap(&self.pool, d1, m1, false);
+                return Err(e);
+            }
+        };
+
+        let cmds = [c1.with_fuse(FUSE_FIRST), c2.with_fuse(FUSE_SECOND)];
+        let (r1, r2) = match queue.issue_batch(&cmds, &self.mmio, self.dstrd) {
+            Ok(cids) => (self.io_wait_raw(&queue, cids[0]), self.io_wait_raw(&queue, cids[1])),
+            Err(e) => (Err(e), Err(e))
+        };
+
+        self.pt_unmap(&self.pool, d1, m1, first.dir() == Some(DataDir::FromDev) && r1.is_ok());
+        self.pt_unmap(&self.pool, d2, m2, second.dir() == Some(DataDir::FromDev) && r2.is_ok());
+        return Ok((r1?, r2?));
+    }
+
+    fn pt_map(&self, pool: &DmaPool<A>, pt: &Passthru, admin: bool) -> Result<(Cmd, Option<DmaMap>, Option<DmaMap>)> {
+        let mut cmd = pt.cmd;
+        let rd = pt.dir() == Some(DataDir::FromDev);
+        if pt.meta().is_some() && cmd.psdt == PSDT_SGL_MPTR {
+            return Err(NVMeError::InvCfg);
+        }
+        if admin && cmd.psdt != PSDT_PRP {
+            return Err(NVMeError::InvCfg);
+        }
+
+        let data = match pt.data() {
+            Some((buf, n)) if n > 0 => {
+                let prp = cmd.psdt == PSDT_PRP;
+                let ((dp1, dp2, psdt), map) = self.stage(buf, n, rd, |b| {
+                    return self.dptr(pool, b, n, prp, !admin).map(|(dp1, dp2, psdt, list)| ((dp1, dp2, psdt), list));
+                })?;
+                (cmd.prp1, cmd.prp2) = (dp1, dp2);
+                if cmd.psdt == PSDT_PRP {
+                    cmd.psdt = psdt;
+                }
+                Some(map)
+            }
+            _ => None
+        };
+
+        let meta = match pt.meta() {
+            Some((buf, n)) if n > 0 => match self.stage(buf, n, rd, |b| self.mptr(b, n).map(|mp| (mp, None))) {
+                Ok((mptr, map)) => {
+                    cmd.mptr = mptr;
+                    Some(map)
+                }
+                Err(e) => {
+                    self.pt_unmap(pool, data, None, false);
+                    return Err(e);
+                }
+            },
+            _ => None
+        };
+
+        return Ok((cmd, data, meta));
+    }
+
+    fn pt_unmap(&self, pool: &DmaPool<A>, data: Option<DmaMap>, meta: Option<DmaMap>, copy: bool) {
+        for map in data.into_iter().chain(meta) {
+            self.unmap_data_in(pool, map, copy);
         }
-        return Err(NVMeError::InvQp);
+    }
+
+    pub fn map_data(&self, buf: usize, n: usize, rd: bool) -> Result<(u64, u64, u8, DmaMap)> {
+        return self.map_data_in(&self.pool, buf, n, rd);
+    }
+
+    pub fn map_data_in(&self, pool: &DmaPool<A>, buf: usize, n: usize, rd: bool) -> Result<(u64, u64, u8, DmaMap)> {
+        let ((dp1, dp2, psdt), map) = self.stage(buf, n, rd, |b| {
+            return self.dptr(pool, b, n, true, true).map(|(dp1, dp2, psdt, list)| ((dp1, dp2, psdt), list));
+        })?;
+        return Ok((dp1, dp2, psdt, map));
+    }
+
+    pub fn unmap_data(&self, map: DmaMap, copy: bool) {
+        self.unmap_data_in(&self.pool, map, copy);
+    }
+
+    pub fn unmap_data_in(&self, pool: &DmaPool<A>, map: DmaMap, copy: bool) {
+        if let Some(list) = map.list {
+            list.free(pool);
+        }
+
+        if let Some((addr, sz)) = map.stage
+            && let Some(pool) = self.bounce.as_ref()
+        {
+            if copy {
+                unsafe { core::ptr::copy_nonoverlapping(addr as *const u8, map.buf as *mut u8, map.len); }
+            }
+            pool.give(addr, sz);
+        }
+    }
+
+    fn stage<T>(
+        &self,
+        buf: usize,
+        n: usize,
+        rd: bool,
+        map: impl Fn(usize) -> Result<(T, Option<PrpList>)>
+    ) -> Result<(T, DmaMap)> {
+        let pool = match (map(buf), self.bounce.as_ref()) {
+            (Err(NVMeError::InvBuf), Some(pool)) => pool,
+            (res, _) => {
+                let (val, list) = res?;
+                return Ok((val, DmaMap { list, stage: None, buf, len: n }));
+            }
+        };
+
+        let (addr, sz) = pool.take(n)?;
+        if !rd {
+            unsafe { core::ptr::copy_nonoverlapping(buf as *const u8, addr as *mut u8, n); }
+        }
+
+        return match map(addr) {
+            Ok((val, list)) => Ok((val, DmaMap { list, stage: Some((addr, sz)), buf, len: n })),
+            Err(e) => {
+                pool.give(addr, sz);
+                Err(e)
+            }
+        };
+    }
+
+    fn dptr(
+        &self,
+        pool: &DmaPool<A>,
+        buf: usize,
+        n: usize,
+        prp: bool,
+        sgl: bool
+    ) -> Result<(u64, u64, u8, Option<PrpList>)> {
+        let pg = self.pg_sz;
+        if !pool.dma_ok(buf, n) {
+            return Err(NVMeError::InvBuf);
+        }
+
+        let res = match prp {
+            true => build_prp(pool, buf, n, pg),
+            false => Err(NVMeError::InvBuf)
+        };
+        return match res {
+            Err(NVMeError::InvBuf) if sgl => match self.data.sgl_align {
+                Some(align) if buf.is_multiple_of(align) && n.is_multiple_of(align) => {
+                    let (dp1, dp2, list) = build_sgl(pool, &sgl_descs(pool, buf, n, pg))?;
+                    Ok((dp1, dp2, PSDT_SGL, list))
+                }
+                _ => Err(NVMeError::InvBuf)
+            },
+            res => res.map(|(prp1, prp2, list)| (prp1, prp2, PSDT_PRP, list))
+        };
+    }
+
+    fn mptr(&self, buf: usize, n: usize) -> Result<u64> {
+        if !self.pool.dma_ok(buf, n) || !buf.is_multiple_of(4) {
+            return Err(NVMeError::InvBuf);
+        }
+
+        let phys = self.pool.virt_to_phys(buf);
+        let mut va = (buf & !(self.pg_sz - 1)) + self.pg_sz;
+        while va < buf + n {
+            if self.pool.virt_to_phys(va) != phys + (va - buf) {
+                return Err(NVMeError::InvBuf);
+            }
+            va += self.pg_sz;
+        }
+        return Ok(phys as u64);
     }
 
     pub fn set_qs_n(&self, nsq: u16, ncq: u16) -> Result<(u16, u16)> {
         let value = (((ncq - 1) as u32) << 16) | ((nsq - 1) as u32);
         let cmd = Cmd::set_feat(crate::id::FT_NQ, value);
         let cqe = self.adm_cmd_res(&cmd)?;
+        self.feats.lock().insert(crate::id::FT_NQ, value);
 
-        let allocd_nsq = ((cqe.dw0 & 0xFFFF) + 1) as u16;
-        let allocd_ncq = (((cqe.dw0 >> 16) & 0xFFFF) + 1) as u16;
+        let allocd_nsq = ((cqe.dw0 & 0xFFFF) + 1).min(u16::MAX as u32) as u16;
+        let allocd_ncq = (((cqe.dw0 >> 16) & 0xFFFF) + 1).min(u16::MAX as u32) as u16;
+        self.nsq.store(allocd_nsq, Ordering::Relaxed);
+        self.ncq.store(allocd_ncq, Ordering::Relaxed);
 
         return Ok((allocd_nsq, allocd_ncq));
     }
 
+    pub fn nsq(&self) -> u16 {
+        return self.nsq.load(Ordering::Relaxed);
+    }
+
+    pub fn ncq(&self) -> u16 {
+        return self.ncq.load(Ordering::Relaxed);
+    }
+
     pub fn set_ioq_cnt(&self, count: u16) -> Result<u16> {
         if count == 0 {
             return Ok(0);
         }
 
-        let (allocated, _) = self.set_qs_n(count, count)?;
-        let target = allocated.min(count);
+        if self.nsq() == 0 || self.ncq() == 0 {
+            self.set_qs_n(u16::MAX, u16::MAX)?;
+        }
+        let target = count.min(self.nsq()).min(self.ncq());
 
         let cur_cnt = self.io.lock().len() as u16;
 
         if target > cur_cnt {
-            let io_size = self.data.mqe as usize;
-            let io_size = io_size.min(256);
-
             for _ in cur_cnt..target {
-                self.new_ioq(io_size)?;
+                self.new_ioq(self.io_sz, QPrio::Medium)?;
             }
         } else if target < cur_cnt {
             let to_remove: Vec<u16> = self.io.lock()
@@ -444,6 +1567,30 @@ impl<A: Dma> Ctrl<A> {
         return Ok(target);
     }
 
+    pub fn arb_mode(&self) -> ArbMode {
+        return self.arb;
+    }
+
+    pub fn cmd_set(&self) -> CmdSet {
+        return self.css;
+    }
+
+    pub fn page_size(&self) -> usize {
+        return self.pg_sz;
+    }
+
+    pub fn contig_queues(&self) -> bool {
+        return self.cqr;
+    }
+
+    pub fn set_arbitration(&self, arb: ArbConfig) -> Result<()> {
+        return self.set_feat(crate::id::FT_ARBITR, arb.value);
+    }
+
+    pub fn get_arbitration(&self) -> Result<ArbConfig> {
+        return Ok(ArbConfig::from_raw(self.get_feat(crate::id::FT_ARBITR)?));
+    }
+
     pub fn en_async_ev(&self) -> Result<()> {
         let mut aec = crate::id::AsyncEventConfig::new();
         aec.en_smart_hlt()
@@ -453,24 +1600,154 @@ impl<A: Dma> Ctrl<A> {
         return self.set_feat(crate::id::FT_ASYNC, aec.value);
     }
 
+    pub fn en_acre(&self) -> Result<()> {
+        let buf_sz = 512;
+        let buf = unsafe { self.pool.alloc(buf_sz) };
+        if buf == 0 {
+            return Err(NVMeError::OoRam);
+        }
+        unsafe {
+            (buf as *mut u8).write_bytes(0, buf_sz);
+            (buf as *mut u8).write(1);
+        }
+
+        let mut cmd = Cmd::set_feat(crate::id::FT_HOST_BEHAV, 0);
+        cmd.prp1 = self.pool.virt_to_phys(buf) as u64;
+        let res = self.admin_cmd(&cmd);
+        unsafe { self.pool.free(buf, buf_sz) };
+
+        self.acre.store(res.is_ok(), Ordering::Relaxed);
+        return res;
+    }
+
+    pub fn acre(&self) -> bool {
+        return self.acre.load(Ordering::Relaxed);
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        return self.retry;
+    }
+
     pub fn block_erase(&self) -> Result<()> {
+        if !self.supports_sanitize(SanitizeKind::BlockErase) {
+            return Err(NVMeError::Unsupported);
+        }
         let cmd = Cmd::sanitise(0x02, false, 0, false, false);
         return self.admin_cmd(&cmd);
     }
 
     pub fn overwrite(&self, passes: u8, invert: bool) -> Result<()> {
+        if !self.supports_sanitize(SanitizeKind::Overwrite) {
+            return Err(NVMeError::Unsupported);
+        }
         let cmd = Cmd::sanitise(0x03, false, passes, invert, false);
         return self.admin_cmd(&cmd);
     }
 
     pub fn crypto_erase(&self) -> Result<()> {
+        if !self.supports_sanitize(SanitizeKind::CryptoErase) {
+            return Err(NVMeError::Unsupported);
+        }
         let cmd = Cmd::sanitise(0x04, false, 0, false, false);
         return self.admin_cmd(&cmd);
     }
 }
 
-impl<A: Dma> Drop for Ctrl<A> {
+impl<A: Dma, M: Mmio> Drop for Ctrl<A, M> {
     fn drop(&mut self) {
         let _ = self.shutdown();
     }
 }
+
+pub struct IoQueuePair<A: Dma, M: Mmio = usize> {
+    ctrl: Arc<Ctrl<A, M>>,
+    queue: Arc<Queue<A>>,
+    prp: Arc<DmaPool<A>>,
+    epoch: u64,
+    win: Window
+}
+
+impl<A: Dma, M: Mmio> IoQueuePair<A, M> {
+    pub fn qid(&self) -> u16 {
+        return self.queue.qid();
+    }
+
+    pub fn queue(&self) -> &Queue<A> {
+        return &self.queue;
+    }
+
+    pub fn ctrl(&self) -> &Arc<Ctrl<A, M>> {
+        return &self.ctrl;
+    }
+
+    pub fn pool(&self) -> &Arc<DmaPool<A>> {
+        return &self.prp;
+    }
+
+    pub fn io_cmd(&mut self, cmd: &Cmd) -> Result<()> {
+        self.rebind();
+        let res = self.ctrl.io_once(&self.queue, cmd);
+        let res = self.ctrl.retry(CmdClass::of_io(cmd), res, || self.ctrl.io_once(&self.queue, cmd));
+
+        self.refresh(res.as_ref().err());
+        return res;
+    }
+
+    pub fn io_batch(&mut self, cmds: &[Cmd]) -> core::result::Result<(), (usize, NVMeError)> {
+        self.rebind();
+        let res = self.ctrl.batch_on(&self.queue, cmds, &mut self.win.cids);
+
+        self.refresh(res.as_ref().err().map(|(_, e)| e));
+        return res;
+    }
+
+    pub fn io_chunks(
+        &mut self,
+        n: usize,
+        copy: bool,
+        mk: impl FnMut(&DmaPool<A>, usize) -> Result<(Cmd, Option<DmaMap>)>
+    ) -> core::result::Result<(), (usize, NVMeError)> {
+        self.rebind();
+        let res = self.ctrl.chunks_on(&self.queue, &self.prp, &mut self.win, n, copy, mk);
+
+        self.refresh(res.as_ref().err().map(|(_, e)| e));
+        return res;
+    }
+
+    pub fn passthru(&mut self, pt: &mut Passthru) -> Result<Cqe> {
+        self.rebind();
+        let (cmd, data, meta) = self.ctrl.pt_map(&self.prp, pt, false)?;
+        let res = self.ctrl.io_issue(&self.queue, &cmd)
+            .and_then(|cid| self.ctrl.io_wait_raw(&self.queue, cid));
+        self.ctrl.pt_unmap(&self.prp, data, meta, pt.dir() == Some(DataDir::FromDev) && res.is_ok());
+
+        self.refresh(res.as_ref().err());
+        return res;
+    }
+
+    fn rebind(&mut self) {
+        let epoch = self.ctrl.epoch.load(Ordering::Acquire);
+        if epoch == self.epoch {
+            return;
+        }
+
+        if let Some(queue) = self.ctrl.own.lock().get(&self.queue.qid()) {
+            self.queue = queue.clone();
+        }
+        self.epoch = epoch;
+    }
+
+    fn refresh(&mut self, err: Option<&NVMeError>) {
+        if let Some(NVMeError::Reset) = err
+            && let Some(queue) = self.ctrl.own.lock().get(&self.queue.qid())
+        {
+            self.queue = queue.clone();
+        }
+    }
+}
+
+impl<A: Dma, M: Mmio> Drop for IoQueuePair<A, M> {
+    fn drop(&mut self) {
+        let _ = self.ctrl.rm_ioq_from(&self.ctrl.own, self.queue.qid());
+    }
+}
@@ -1,14 +1,18 @@
-use crate::{Ctrl, Dma, Ns, Result};
+use crate::{Ctrl, CtrlConfig, Dma, Mmio, Ns, Result};
 use alloc::{vec::Vec, sync::Arc};
 
-pub struct NVMeDev<A: Dma> {
-    ctrl: Arc<Ctrl<A>>,
-    nss: Vec<Arc<Ns<A>>>
+pub struct NVMeDev<A: Dma, M: Mmio = usize> {
+    ctrl: Arc<Ctrl<A, M>>,
+    nss: Vec<Arc<Ns<A, M>>>
 }
 
-impl<A: Dma> NVMeDev<A> {
-    pub fn new(mmio: usize, alloc: A) -> Result<Arc<Self>> {
-        let ctrl = Arc::new(Ctrl::new(mmio, alloc)?);
+impl<A: Dma, M: Mmio> NVMeDev<A, M> {
+    pub fn new(mmio: M, alloc: A, cfg: &CtrlConfig) -> Result<Arc<Self>> {
+        return Self::from_ctrl(Ctrl::new(mmio, alloc, cfg)?);
+    }
+
+    fn from_ctrl(ctrl: Ctrl<A, M>) -> Result<Arc<Self>> {
+        let ctrl = Arc::new(ctrl);
 
         let mut nss = Vec::new();
         for nsid in ctrl.reg_nss()? {
@@ -20,11 +24,11 @@ impl<A: Dma> NVMeDev<A> {
         return Ok(Arc::new(Self { ctrl, nss }));
     }
 
-    pub fn ctrl(&self) -> Arc<Ctrl<A>> {
+    pub fn ctrl(&self) -> Arc<Ctrl<A, M>> {
         return self.ctrl.clone();
     }
 
-    pub fn ns(&self, nsid: u32) -> Option<Arc<Ns<A>>> {
+    pub fn ns(&self, nsid: u32) -> Option<Arc<Ns<A, M>>> {
         for ns in &self.nss {
             if ns.id() == nsid {
                 return Some(ns.clone());
@@ -33,7 +37,7 @@ impl<A: Dma> NVMeDev<A> {
         return None;
     }
 
-    pub fn ns_list(&self) -> &[Arc<Ns<A>>] {
+    pub fn ns_list(&self) -> &[Arc<Ns<A, M>>] {
         return &self.nss;
     }
 }
@@ -0,0 +1,995 @@
+use crate::{
+    cmd::Sqe, id::{self, CtrlId, LbaFormat, LogSmart, NsId}, queue::Cqe, reg, Mmio,
+    SglDesc, SGL_BIT_BUCKET, SGL_DATA, SGL_LAST_SEG, SGL_SEGMENT
+};
+use core::{mem::zeroed, slice, sync::atomic::{fence, Ordering}};
+use alloc::{collections::{BTreeMap, BTreeSet, VecDeque}, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+const BAR_SZ: usize = 0x2000;
+const MAX_QID: u16 = 63;
+
+const SC_OK: u16 = 0x000;
+const SC_INV_OPC: u16 = 0x001;
+const SC_INV_FIELD: u16 = 0x002;
+const SC_ABORT_REQ: u16 = 0x007;
+const SC_FUSED_FAIL: u16 = 0x009;
+const SC_INV_NS: u16 = 0x00B;
+const SC_PRP_OFF: u16 = 0x013;
+const SC_LBA_RANGE: u16 = 0x080;
+const SC_CQ_INV: u16 = 0x100;
+const SC_QID_INV: u16 = 0x101;
+const SC_QSZ_INV: u16 = 0x102;
+const SC_LOG_INV: u16 = 0x109;
+const SC_IV_INV: u16 = 0x108;
+const SC_QDEL_INV: u16 = 0x10C;
+const SC_CMP_FAIL: u16 = 0x285;
+
+pub struct EmuCfg {
+    pub mqes: u16,
+    pub to: u8,
+    pub mdts: u8,
+    pub nqs: u16,
+    pub nvecs: u16,
+    pub wrr: bool,
+    pub mpsmin: u8,
+    pub mpsmax: u8,
+    pub cqr: bool,
+    pub sgls: u32,
+    pub crdt: [u16; 3],
+    pub oncs: u16,
+    pub oacs: u16,
+    pub sanicap: u32,
+    pub phys_off: usize,
+    pub nss: Vec<(usize, u64)>
+}
+
+impl EmuCfg {
+    pub fn new() -> Self {
+        return Self {
+            mqes: 1023,
+            to: 20,
+            mdts: 5,
+            nqs: 16,
+            nvecs: 8,
+            wrr: true,
+            mpsmin: 0,
+            mpsmax: 4,
+            cqr: true,
+            sgls: 0,
+            crdt: [0; 3],
+            oncs: (1 << 0) | (1 << 2) | (1 << 3) | (1 << 7),
+            oacs: 0,
+            sanicap: 0x7,
+            phys_off: 0,
+            nss: Vec::new()
+        };
+    }
+
+    pub fn add_ns(&mut self, blk_sz: usize, blk_cnt: u64) -> &mut Self {
+        self.nss.push((blk_sz, blk_cnt));
+        return self;
+    }
+}
+
+struct ESq {
+    ring: Vec<u64>,
+    size: u16,
+    cqid: u16,
+    head: u16,
+    prio: u8
+}
+
+struct ECq {
+    ring: Vec<u64>,
+    size: u16,
+    tail: u16,
+    phase: bool,
+    iv: Option<u16>
+}
+
+struct ENs {
+    blk_sz: usize,
+    data: Vec<u8>
+}
+
+struct Core {
+    regs: Vec<u32>,
+    mdts: u8,
+    sgls: u32,
+    crdt: [u16; 3],
+    oncs: u16,
+    oacs: u16,
+    sanicap: u32,
+    nqs: u16,
+    nvecs: u16,
+    off: usize,
+    en: bool,
+    hang: bool,
+    fatal: bool,
+    shn: u32,
+    sqs: BTreeMap<u16, ESq>,
+    cqs: BTreeMap<u16, ECq>,
+    stalled: BTreeSet<u16>,
+    no_abort: bool,
+    aborted: BTreeSet<(u16, u16)>,
+    fails: VecDeque<u16>,
+    adm_fails: VecDeque<(u8, u16)>,
+    fused: BTreeSet<u16>,
+    nss: Vec<ENs>,
+    feats: BTreeMap<u8, u32>,
+    intms: u32,
+    irq_pend: u32,
+    irqs: BTreeMap<u16, u64>,
+    sq_dbs: BTreeMap<u16, u64>,
+    rd_bytes: u64,
+    wr_bytes: u64,
+    resets: u64
+}
+
+impl Core {
+    fn r32(&self, off: usize) -> u32 {
+        return self.regs.get(off / 4).copied().unwrap_or(0);
+    }
+
+    fn w32(&mut self, off: usize, val: u32) {
+        if let Some(r) = self.regs.get_mut(off / 4) {
+            *r = val;
+        }
+    }
+
+    fn r64(&self, off: usize) -> u64 {
+        return ((self.r32(off + 4) as u64) << 32) | self.r32(off) as u64;
+    }
+
+    fn mmio_write(&mut self, off: usize, val: u32) {
+        match off {
+            reg::CAP | 0x04 | reg::VS | reg::CSTS => {}
+            _ if self.hang || (self.fatal && off != reg::CC) => self.w32(off, val),
+            reg::CC => {
+                self.w32(off, val);
+                self.cc_changed();
+            }
+            reg::INTMS => self.set_intms(self.intms | val),
+            reg::INTMC => self.set_intms(self.intms & !val),
+            _ if off >= 0x1000 => {
+                self.w32(off, val);
+                let qid = ((off - 0x1000) / 8) as u16;
+                if self.en && (off - 0x1000).is_multiple_of(8) {
+                    *self.sq_dbs.entry(qid).or_insert(0) += 1;
+                    self.drain(qid);
+                } else if self.en {
+                    self.drain_all();
+                }
+            }
+            _ => self.w32(off, val)
+        }
+    }
+
+    fn set_intms(&mut self, intms: u32) {
+        self.intms = intms;
+        self.w32(reg::INTMS, intms);
+        self.w32(reg::INTMC, intms);
+
+        let fire = self.irq_pend & !intms;
+        self.irq_pend &= intms;
+        for iv in 0..32 {
+            if fire & (1 << iv) != 0 {
+                *self.irqs.entry(iv).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn raise(&mut self, iv: u16) {
+        if iv < 32 && self.intms & (1 << iv) != 0 {
+            self.irq_pend |= 1 << iv;
+        } else {
+            *self.irqs.entry(iv).or_insert(0) += 1;
+        }
+    }
+
+    fn drain_all(&mut self) {
+        let qids = self.sqs.keys().copied().collect::<Vec<u16>>();
+        for qid in qids {
+            self.drain(qid);
+        }
+    }
+
+    fn cc_changed(&mut self) {
+        let cc = self.r32(reg::CC);
+        let en = (cc & reg::CC_EN) != 0;
+
+        if en && !self.en {
+            self.enable();
+        } else if !en && self.en {
+            self.disable();
+        }
+
+        let shn = cc & (0x3 << 14);
+        if shn != self.shn {
+            self.shn = shn;
+            let csts = self.r32(reg::CSTS) & !(0x3 << 2);
+            if shn != 0 {
+                self.w32(reg::CSTS, csts | reg::CSTS_SHST_COMPLETE);
+            } else {
+                self.w32(reg::CSTS, csts);
+            }
+        }
+    }
+
+    fn enable(&mut self) {
+        let cap = self.r64(reg::CAP);
+        let mps = ((self.r32(reg::CC) >> reg::CC_MPS_SHIFT) & 0xF) as u64;
+        if mps < (cap >> reg::CAP_MPSMIN_SHIFT) & reg::CAP_MPSMIN_MASK
+            || mps > (cap >> reg::CAP_MPSMAX_SHIFT) & reg::CAP_MPSMAX_MASK
+        {
+            self.fatal = true;
+            let csts = self.r32(reg::CSTS);
+            self.w32(reg::CSTS, csts | reg::CSTS_CFS);
+            return;
+        }
+
+        let aqa = self.r32(reg::AQA);
+        let asqs = ((aqa & 0xFFF) + 1) as u16;
+        let acqs = (((aqa >> 16) & 0xFFF) + 1) as u16;
+
+        self.sqs.clear();
+        self.cqs.clear();
+        self.sqs.insert(0, ESq { ring: vec![self.r64(reg::ASQ)], size: asqs, cqid: 0, head: 0, prio: 0 });
+        self.cqs.insert(0, ECq { ring: vec![self.r64(reg::ACQ)], size: acqs, tail: 0, phase: true, iv: Some(0) });
+
+        for db in 0..=(2 * MAX_QID as usize + 1) {
+            self.w32(0x1000 + db * 4, 0);
+        }
+
+        self.en = true;
+        let csts = self.r32(reg::CSTS) & !(0x3 << 2);
+        self.w32(reg::CSTS, csts | reg::CSTS_RDY);
+    }
+
+    fn disable(&mut self) {
+        if self.en {
+            self.resets += 1;
+        }
+        self.en = false;
+        self.fatal = false;
+        self.sqs.clear();
+        self.cqs.clear();
+        self.aborted.clear();
+        self.fused.clear();
+        self.feats.clear();
+        self.irq_pend = 0;
+        self.set_intms(0);
+
+        let csts = self.r32(reg::CSTS);
+        self.w32(reg::CSTS, csts & !(reg::CSTS_RDY | reg::CSTS_CFS));
+    }
+
+    fn drain(&mut self, qid: u16) {
+        if self.stalled.contains(&qid) {
+            return;
+        }
+
+        let pg = self.pg();
+        loop {
+            let (addr, head, size, cqid) = match self.sqs.get(&qid) {
+                Some(sq) => (ring_pa(&sq.ring, pg, sq.head as usize * 64), sq.head, sq.size, sq.cqid),
+                None => return
+            };
+
+            let tail = self.r32(reg::doorbell_sq(qid, 0)) as u16;
+            if tail >= size || tail == head {
+                return;
+            }
+
+            let cq = match self.cqs.get(&cqid) {
+                Some(cq) => cq,
+                None => return
+            };
+            let cq_head = self.r32(reg::doorbell_cq(cqid, 0)) as u16;
+            if (cq.tail + 1) % cq.size == cq_head {
+                return;
+            }
+
+            let sqe = unsafe { (self.host(addr) as *const Sqe).read_volatile() };
+            if let Some(sq) = self.sqs.get_mut(&qid) {
+                sq.head = (head + 1) % size;
+            }
+
+            let cid = (sqe.cdw0 >> 16) as u16;
+            let fail = if qid != 0 {
+                self.fails.pop_front()
+            } else if self.adm_fails.front().is_some_and(|&(opc, _)| opc == sqe.cdw0 as u8) {
+                self.adm_fails.pop_front().map(|(_, st)| st)
+            } else {
+                None
+            };
+            if let Some(st) = fail {
+                let acre = self.feats.get(&id::FT_HOST_BEHAV) == Some(&1);
+                let st = if acre { st } else { st & !(0x3 << 11) };
+                self.complete(cqid, qid, cid, st, 0);
+                continue;
+            }
+
+            let fuse = (sqe.cdw0 >> 8) & 0x3;
+            let res = if self.aborted.remove(&(qid, cid)) {
+                Some((SC_ABORT_REQ, 0))
+            } else if fuse == 2 && self.fused.remove(&qid) {
+                Some((SC_FUSED_FAIL, 0))
+            } else if qid == 0 {
+                self.admin(&sqe)
+            } else {
+                self.io(&sqe)
+            };
+            if fuse == 1 && res.is_some_and(|(st, _)| st != SC_OK) {
+                self.fused.insert(qid);
+            }
+            if let Some((st, dw0)) = res {
+                self.post(cqid, qid, cid, st, dw0);
+            }
+        }
+    }
+
+    fn post(&mut self, cqid: u16, sqid: u16, cid: u16, st: u16, dw0: u32) {
+        let dnr = if st != SC_OK && st != SC_ABORT_REQ { 1 << 14 } else { 0 };
+        self.complete(cqid, sqid, cid, st | dnr, dw0);
+    }
+
+    fn complete(&mut self, cqid: u16, sqid: u16, cid: u16, st: u16, dw0: u32) {
+        let sqhd = self.sqs.get(&sqid).map(|sq| sq.head).unwrap_or(0);
+        let off = self.off;
+        let pg = self.pg();
+        let cq = match self.cqs.get_mut(&cqid) {
+            Some(cq) => cq,
+            None => return
+        };
+
+        let cqe = Cqe {
+            dw0,
+            dw1: 0,
+            sqhd,
+            sqid,
+            cid,
+            sf: (st << 1) | cq.phase as u16
+        };
+
+        let ptr = ring_pa(&cq.ring, pg, cq.tail as usize * 16) as usize;
+        unsafe { (ptr.wrapping_sub(off) as *mut Cqe).write_volatile(cqe); }
+        fence(Ordering::Release);
+
+        cq.tail = (cq.tail + 1) % cq.size;
+        if cq.tail == 0 {
+            cq.phase = !cq.phase;
+        }
+
+        if let Some(iv) = cq.iv {
+            self.raise(iv);
+        }
+    }
+
+    fn host(&self, pa: u64) -> usize {
+        return (pa as usize).wrapping_sub(self.off);
+    }
+
+    fn pg(&self) -> usize {
+        return 4096 << ((self.r32(reg::CC) >> reg::CC_MPS_SHIFT) & 0xF);
+    }
+
+    fn segs(&self, sqe: &Sqe, len: usize) -> Vec<(Option<usize>, usize)> {
+        if (sqe.cdw0 >> 14) & 0x3 != 0 {
+            return self.sgl_segs(sqe, len);
+        }
+
+        let pg = self.pg();
+
+        let mut segs = Vec::new();
+        let first = len.min(pg - (sqe.prp1 as usize % pg));
+        segs.push((Some(self.host(sqe.prp1)), first));
+
+        let mut left = len - first;
+        if left == 0 {
+            return segs;
+        }
+
+        if left <= pg {
+            segs.push((Some(self.host(sqe.prp2)), left));
+            return segs;
+        }
+
+        let per_pg = pg / 8;
+        let mut idx = (sqe.prp2 as usize % pg) / 8;
+        let mut list = self.host(sqe.prp2 - (idx * 8) as u64) as *const u64;
+        while left > 0 {
+            let ent = unsafe { list.add(idx).read_volatile() };
+            if idx == per_pg - 1 && left > pg {
+                list = self.host(ent) as *const u64;
+                idx = 0;
+                continue;
+            }
+
+            let n = left.min(pg);
+            segs.push((Some(self.host(ent)), n));
+            left -= n;
+            idx += 1;
+        }
+
+        return segs;
+    }
+
+    fn sgl_segs(&self, sqe: &Sqe, len: usize) -> Vec<(Option<usize>, usize)> {
+        let mut segs = Vec::new();
+        let mut descs = vec![SglDesc {
+            addr: sqe.prp1,
+            len: sqe.prp2 as u32,
+            rsvd: [0; 3],
+            id: (sqe.prp2 >> 56) as u8
+        }];
+
+        let mut left = len;
+        let mut i = 0;
+        while i < descs.len() && left > 0 {
+            let desc = descs[i];
+            i += 1;
+
+            match desc.kind() {
+                SGL_DATA | SGL_BIT_BUCKET => {
+                    let n = (desc.len as usize).min(left);
+                    let va = (desc.kind() == SGL_DATA).then(|| self.host(desc.addr));
+                    segs.push((va, n));
+                    left -= n;
+                }
+                SGL_SEGMENT | SGL_LAST_SEG => {
+                    let list = self.host(desc.addr) as *const SglDesc;
+                    descs = (0..desc.len as usize / size_of::<SglDesc>())
+                        .map(|k| unsafe { list.add(k).read_volatile() })
+                        .collect();
+                    i = 0;
+                }
+                _ => break
+            }
+        }
+
+        return segs;
+    }
+
+    fn copy_out(&self, sqe: &Sqe, data: &[u8]) {
+        let mut done = 0;
+        for (va, n) in self.segs(sqe, data.len()) {
+            if let Some(va) = va {
+                unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), va as *mut u8, n); }
+            }
+            done += n;
+        }
+    }
+
+    fn copy_in(&self, sqe: &Sqe, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        let mut done = 0;
+        for (va, n) in self.segs(sqe, len) {
+            if let Some(va) = va {
+                unsafe { core::ptr::copy_nonoverlapping(va as *const u8, data[done..].as_mut_ptr(), n); }
+            }
+            done += n;
+        }
+        return data;
+    }
+
+    fn admin(&mut self, sqe: &Sqe) -> Option<(u16, u32)> {
+        let opc = (sqe.cdw0 & 0xFF) as u8;
+        let res = match opc {
+            0x00 => self.sq_del(sqe),
+            0x01 => self.sq_create(sqe),
+            0x02 => self.get_log(sqe),
+            0x04 => self.cq_del(sqe),
+            0x05 => self.cq_create(sqe),
+            0x06 => self.identify(sqe),
+            0x08 => self.abort(sqe),
+            0x09 => self.set_feat(sqe),
+            0x0A => self.get_feat(sqe),
+            0x0C => return None,
+            0x84 => self.sanitise(),
+            _ => (SC_INV_OPC, 0)
+        };
+        return Some(res);
+    }
+
+    fn abort(&mut self, sqe: &Sqe) -> (u16, u32) {
+        let sqid = (sqe.cdw10 & 0xFFFF) as u16;
+        let cid = (sqe.cdw10 >> 16) as u16;
+
+        let (ring, head, size, cqid) = match self.sqs.get(&sqid) {
+            Some(sq) if !self.no_abort => (sq.ring.clone(), sq.head, sq.size, sq.cqid),
+            _ => return (SC_OK, 1)
+        };
+        let pg = self.pg();
+        let tail = self.r32(reg::doorbell_sq(sqid, 0)) as u16;
+
+        let mut pos = head;
+        while pos != tail && tail < size {
+            let cdw0 = unsafe { (self.host(ring_pa(&ring, pg, pos as usize * 64)) as *const u32).read_volatile() };
+            if (cdw0 >> 16) as u16 == cid {
+                if pos == head {
+                    if let Some(sq) = self.sqs.get_mut(&sqid) {
+                        sq.head = (head + 1) % size;
+                    }
+                    self.post(cqid, sqid, cid, SC_ABORT_REQ, 0);
+                } else {
+                    self.aborted.insert((sqid, cid));
+                }
+                return (SC_OK, 0);
+            }
+            pos = (pos + 1) % size;
+        }
+
+        return (SC_OK, 1);
+    }
+
+    fn sq_create(&mut self, sqe: &Sqe) -> (u16, u32) {
+        let qid = (sqe.cdw10 & 0xFFFF) as u16;
+        let size = ((sqe.cdw10 >> 16) + 1) as u16;
+        let cqid = (sqe.cdw11 >> 16) as u16;
+
+        if qid == 0 || qid > self.nqs.min(MAX_QID) || self.sqs.contains_key(&qid) {
+            return (SC_QID_INV, 0);
+        }
+        if size < 2 || size as u32 > self.mqes() + 1 {
+            return (SC_QSZ_INV, 0);
+        }
+        if cqid == 0 || !self.cqs.contains_key(&cqid) {
+            return (SC_CQ_INV, 0);
+        }
+        let ring = match self.ring(sqe, size as usize * 64) {
+            Ok(ring) => ring,
+            Err(st) => return (st, 0)
+        };
+
+        let prio = ((sqe.cdw11 >> 1) & 0x3) as u8;
+        self.sqs.insert(qid, ESq { ring, size, cqid, head: 0, prio });
+        return (SC_OK, 0);
+    }
+
+    fn sq_del(&mut self, sqe: &Sqe) -> (u16, u32) {
+        let qid = (sqe.cdw10 & 0xFFFF) as u16;
+        if qid == 0 || self.sqs.remove(&qid).is_none() {
+            return (SC_QID_INV, 0);
+        }
+        self.w32(reg::doorbell_sq(qid, 0), 0);
+        return (SC_OK, 0);
+    }
+
+    fn cq_create(&mut self, sqe: &Sqe) -> (u16, u32) {
+        let qid = (sqe.cdw10 & 0xFFFF) as u16;
+        let size = ((sqe.cdw10 >> 16) + 1) as u16;
+
+        if qid == 0 || qid > self.nqs.min(MAX_QID) || self.cqs.contains_key(&qid) {
+            return (SC_QID_INV, 0);
+        }
+        if size < 2 || size as u32 > self.mqes() + 1 {
+            return (SC_QSZ_INV, 0);
+        }
+        let ring = match self.ring(sqe, size as usize * 16) {
+            Ok(ring) => ring,
+            Err(st) => return (st, 0)
+        };
+
+        let iv = (sqe.cdw11 & 0x2 != 0).then_some((sqe.cdw11 >> 16) as u16);
+        if iv.is_some_and(|iv| iv >= self.nvecs) {
+            return (SC_IV_INV, 0);
+        }
+
+        self.cqs.insert(qid, ECq { ring, size, tail: 0, phase: true, iv });
+        return (SC_OK, 0);
+    }
+
+    fn ring(&self, sqe: &Sqe, bytes: usize) -> Result<Vec<u64>, u16> {
+        let pg = self.pg();
+        if sqe.cdw11 & 0x1 != 0 {
+            if !(sqe.prp1 as usize).is_multiple_of(pg) {
+                return Err(SC_PRP_OFF);
+            }
+            return Ok(vec![sqe.prp1]);
+        }
+
+        if self.r64(reg::CAP) & reg::CAP_CQR != 0 {
+            return Err(SC_INV_FIELD);
+        }
+        if !(sqe.prp1 as usize).is_multiple_of(pg) {
+            return Err(SC_PRP_OFF);
+        }
+
+        let list = self.host(sqe.prp1) as *const u64;
+        let ring = (0..bytes.div_ceil(pg))
+            .map(|i| unsafe { list.add(i).read_volatile() })
+            .collect::<Vec<u64>>();
+        if ring.iter().any(|pa| !(*pa as usize).is_multiple_of(pg)) {
+            return Err(SC_PRP_OFF);
+        }
+        return Ok(ring);
+    }
+
+    fn cq_del(&mut self, sqe: &Sqe) -> (u16, u32) {
+        let qid = (sqe.cdw10 & 0xFFFF) as u16;
+        if qid == 0 || !self.cqs.contains_key(&qid) {
+            return (SC_QID_INV, 0);
+        }
+        if self.sqs.values().any(|sq| sq.cqid == qid) {
+            return (SC_QDEL_INV, 0);
+        }
+
+        self.cqs.remove(&qid);
+        self.w32(reg::doorbell_cq(qid, 0), 0);
+        return (SC_OK, 0);
+    }
+
+    fn mqes(&self) -> u32 {
+        return (self.r64(reg::CAP) & reg::CAP_MQES_MASK) as u32;
+    }
+
+    fn identify(&self, sqe: &Sqe) -> (u16, u32) {
+        let mut buf = vec![0u8; 4096];
+
+        match sqe.cdw10 & 0xFF {
+            0x00 => {
+                let ns = match self.ns(sqe.nsid) {
+                    Some(ns) => ns,
+                    None => return (SC_INV_NS, 0)
+                };
+
+                let mut nsid: NsId = unsafe { zeroed() };
+                let blk_cnt = (ns.data.len() / ns.blk_sz) as u64;
+                nsid.nsze = blk_cnt;
+                nsid.ncap = blk_cnt;
+                nsid.nuse = blk_cnt;
+                nsid.lbaf[0] = LbaFormat {
+                    ms: 0,
+                    lbads: ns.blk_sz.trailing_zeros() as u8,
+                    rp: 0
+                };
+                buf.copy_from_slice(as_bytes(&nsid));
+            }
+            0x01 => {
+                let mut ctrl: CtrlId = unsafe { zeroed() };
+                ctrl.vid = 0x1B36;
+                ctrl.ssvid = 0x1AF4;
+                pad(&mut ctrl.sn, b"EMU00000001");
+                pad(&mut ctrl.mn, b"nvme-oxide emulated controller");
+                pad(&mut ctrl.fr, b"1.0");
+                ctrl.mdts = self.mdts;
+                ctrl.sgls = self.sgls;
+                [ctrl.crdt1, ctrl.crdt2, ctrl.crdt3] = self.crdt;
+                ctrl.cntlid = 1;
+                ctrl.ver = 0x0001_0400;
+                ctrl.rtd3e = 1_000_000;
+                ctrl.acl = 3;
+                ctrl.aerl = 3;
+                ctrl.lpa = 0x02;
+                ctrl.elpe = 63;
+                ctrl.sqes = 0x66;
+                ctrl.cqes = 0x44;
+                ctrl.nn = self.nss.len() as u32;
+                ctrl.oncs = self.oncs;
+                ctrl.oacs = self.oacs;
+                ctrl.sanicap = self.sanicap;
+                ctrl.vwc = 1;
+                buf.copy_from_slice(as_bytes(&ctrl));
+            }
+            0x02 => {
+                for (n, nsid) in ((sqe.nsid as usize + 1)..=self.nss.len()).enumerate() {
+                    buf[n * 4..n * 4 + 4].copy_from_slice(&(nsid as u32).to_le_bytes());
+                }
+            }
+            0x03 => {
+                if self.ns(sqe.nsid).is_none() {
+                    return (SC_INV_NS, 0);
+                }
+            }
+            _ => return (SC_INV_FIELD, 0)
+        }
+
+        self.copy_out(sqe, &buf);
+        return (SC_OK, 0);
+    }
+
+    fn get_log(&self, sqe: &Sqe) -> (u16, u32) {
+        let lid = (sqe.cdw10 & 0xFF) as u8;
+        let numd = ((sqe.cdw10 >> 16) | ((sqe.cdw11 & 0xFFFF) << 16)) as usize + 1;
+        let mut buf = vec![0u8; numd * 4];
+
+        match lid {
+            id::LOG_ERR | id::LOG_FW => {}
+            id::LOG_SMART => {
+                let mut smart: LogSmart = unsafe { zeroed() };
+                smart.temp = 300u16.to_le_bytes();
+                smart.avl_spr = 100;
+                smart.spr_thrs = 10;
+                smart.data_rd = (self.rd_bytes.div_ceil(512_000) as u128).to_le_bytes();
+                smart.data_wr = (self.wr_bytes.div_ceil(512_000) as u128).to_le_bytes();
+                smart.pwr_cyc = 1u128.to_le_bytes();
+
+                let src = as_bytes(&smart);
+                let n = src.len().min(buf.len());
+                buf[..n].copy_from_slice(&src[..n]);
+            }
+            _ => return (SC_LOG_INV, 0)
+        }
+
+        self.copy_out(sqe, &buf);
+        return (SC_OK, 0);
+    }
+
+    fn set_feat(&mut self, sqe: &Sqe) -> (u16, u32) {
+        let fid = (sqe.cdw10 & 0xFF) as u8;
+
+        if fid == id::FT_NQ {
+            let max = (self.nqs - 1) as u32;
+            let nsq = (sqe.cdw11 & 0xFFFF).min(max);
+            let ncq = (sqe.cdw11 >> 16).min(max);
+            let val = (ncq << 16) | nsq;
+            self.feats.insert(fid, val);
+            return (SC_OK, val);
+        }
+
+        if fid == id::FT_HOST_BEHAV {
+            let data = self.copy_in(sqe, 512);
+            self.feats.insert(fid, data[0] as u32);
+            return (SC_OK, 0);
+        }
+
+        self.feats.insert(fid, sqe.cdw11);
+        return (SC_OK, 0);
+    }
+
+    fn get_feat(&self, sqe: &Sqe) -> (u16, u32) {
+        let fid = (sqe.cdw10 & 0xFF) as u8;
+
+        if fid == id::FT_NQ && !self.feats.contains_key(&fid) {
+            let max = (self.nqs - 1) as u32;
+            return (SC_OK, (max << 16) | max);
+        }
+
+        return (SC_OK, self.feats.get(&fid).copied().unwrap_or(0));
+    }
+
+    fn sanitise(&mut self) -> (u16, u32) {
+        for ns in &mut self.nss {
+            ns.data.fill(0);
+        }
+        return (SC_OK, 0);
+    }
+
+    fn ns(&self, nsid: u32) -> Option<&ENs> {
+        if nsid == 0 {
+            return None;
+        }
+        return self.nss.get(nsid as usize - 1);
+    }
+
+    fn range(&self, nsid: u32, slba: u64, nlb: u64) -> Result<(usize, usize), u16> {
+        let ns = self.ns(nsid).ok_or(SC_INV_NS)?;
+        let blk_cnt = (ns.data.len() / ns.blk_sz) as u64;
+        if slba.checked_add(nlb).is_none_or(|end| end > blk_cnt) {
+            return Err(SC_LBA_RANGE);
+        }
+        return Ok((slba as usize * ns.blk_sz, nlb as usize * ns.blk_sz));
+    }
+
+    fn mdts_ok(&self, (off, len): (usize, usize)) -> Result<(usize, usize), u16> {
+        let min_pg = 4096 << ((self.r64(reg::CAP) >> reg::CAP_MPSMIN_SHIFT) & reg::CAP_MPSMIN_MASK);
+        if self.mdts != 0 && len > min_pg << self.mdts {
+            return Err(SC_INV_FIELD);
+        }
+        return Ok((off, len));
+    }
+
+    fn io(&mut self, sqe: &Sqe) -> Option<(u16, u32)> {
+        let opc = (sqe.cdw0 & 0xFF) as u8;
+        let slba = (sqe.cdw10 as u64) | ((sqe.cdw11 as u64) << 32);
+        let nlb = (sqe.cdw12 & 0xFFFF) as u64 + 1;
+
+        if self.ns(sqe.nsid).is_none() {
+            return Some((SC_INV_NS, 0));
+        }
+
+        let res = match opc {
+            0x00 => Ok(()),
+            0x01 => self.range(sqe.nsid, slba, nlb).and_then(|r| self.mdts_ok(r)).map(|(off, len)| {
+                let data = self.copy_in(sqe, len);
+                self.nss[sqe.nsid as usize - 1].data[off..off + len].copy_from_slice(&data);
+                self.wr_bytes += len as u64;
+            }),
+            0x02 => self.range(sqe.nsid, slba, nlb).and_then(|r| self.mdts_ok(r)).map(|(off, len)| {
+                let ns = &self.nss[sqe.nsid as usize - 1];
+                self.copy_out(sqe, &ns.data[off..off + len]);
+                self.rd_bytes += len as u64;
+            }),
+            0x05 => self.range(sqe.nsid, slba, nlb).and_then(|r| self.mdts_ok(r)).and_then(|(off, len)| {
+                let data = self.copy_in(sqe, len);
+                if self.nss[sqe.nsid as usize - 1].data[off..off + len] != data[..] {
+                    return Err(SC_CMP_FAIL);
+                }
+                return Ok(());
+            }),
+            0x08 => self.range(sqe.nsid, slba, nlb).map(|(off, len)| {
+                self.nss[sqe.nsid as usize - 1].data[off..off + len].fill(0);
+            }),
+            0x09 => self.dsm(sqe),
+            0x0C => self.range(sqe.nsid, slba, nlb).map(|_| ()),
+            _ => Err(SC_INV_OPC)
+        };
+
+        return Some(match res {
+            Ok(()) => (SC_OK, 0),
+            Err(st) => (st, 0)
+        });
+    }
+
+    fn dsm(&mut self, sqe: &Sqe) -> Result<(), u16> {
+        let nr = (sqe.cdw10 & 0xFF) as usize + 1;
+        let raw = self.copy_in(sqe, nr * 16);
+
+        if (sqe.cdw11 & 0x4) == 0 {
+            return Ok(());
+        }
+
+        for ent in raw.chunks_exact(16) {
+            let nlb = u32::from_le_bytes(ent[4..8].try_into().unwrap()) as u64;
+            let slba = u64::from_le_bytes(ent[8..16].try_into().unwrap());
+            let (off, len) = self.range(sqe.nsid, slba, nlb)?;
+            self.nss[sqe.nsid as usize - 1].data[off..off + len].fill(0);
+        }
+        return Ok(());
+    }
+}
+
+fn ring_pa(ring: &[u64], pg: usize, off: usize) -> u64 {
+    if ring.len() == 1 {
+        return ring[0] + off as u64;
+    }
+    return ring[off / pg] + (off % pg) as u64;
+}
+
+fn as_bytes<T>(val: &T) -> &[u8] {
+    return unsafe { slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+}
+
+fn pad(dst: &mut [u8], src: &[u8]) {
+    dst.fill(b' ');
+    dst[..src.len()].copy_from_slice(src);
+}
+
+#[derive(Clone)]
+pub struct Emu {
+    core: Arc<Mutex<Core>>
+}
+
+impl Emu {
+    pub fn new(cfg: &EmuCfg) -> Self {
+        let mut regs = vec![0u32; BAR_SZ / 4];
+        let cap = (cfg.mqes as u64)
+            | if cfg.wrr { reg::CAP_AMS_WRR } else { 0 }
+            | if cfg.cqr { reg::CAP_CQR } else { 0 }
+            | ((cfg.to as u64) << reg::CAP_TO_SHIFT)
+            | reg::CAP_CSS_NVM
+            | ((cfg.mpsmin as u64) << reg::CAP_MPSMIN_SHIFT)
+            | ((cfg.mpsmax as u64) << reg::CAP_MPSMAX_SHIFT);
+        regs[reg::CAP / 4] = cap as u32;
+        regs[reg::CAP / 4 + 1] = (cap >> 32) as u32;
+        regs[reg::VS / 4] = 0x0001_0400;
+
+        let nss = cfg.nss.iter().map(|&(blk_sz, blk_cnt)| ENs {
+            blk_sz,
+            data: vec![0u8; blk_sz * blk_cnt as usize]
+        }).collect();
+
+        let core = Arc::new(Mutex::new(Core {
+            regs,
+            mdts: cfg.mdts,
+            sgls: cfg.sgls,
+            crdt: cfg.crdt,
+            oncs: cfg.oncs,
+            oacs: cfg.oacs,
+            sanicap: cfg.sanicap,
+            nqs: cfg.nqs.max(2),
+            nvecs: cfg.nvecs.max(1),
+            off: cfg.phys_off,
+            en: false,
+            hang: false,
+            fatal: false,
+            shn: 0,
+            sqs: BTreeMap::new(),
+            cqs: BTreeMap::new(),
+            stalled: BTreeSet::new(),
+            no_abort: false,
+            aborted: BTreeSet::new(),
+            fails: VecDeque::new(),
+            adm_fails: VecDeque::new(),
+            fused: BTreeSet::new(),
+            nss,
+            feats: BTreeMap::new(),
+            intms: 0,
+            irq_pend: 0,
+            irqs: BTreeMap::new(),
+            sq_dbs: BTreeMap::new(),
+            rd_bytes: 0,
+            wr_bytes: 0,
+            resets: 0
+        }));
+
+        return Self { core };
+    }
+
+    pub fn set_hang(&self, hang: bool) {
+        let mut core = self.core.lock();
+        core.hang = hang;
+        if !hang {
+            core.cc_changed();
+            if core.en {
+                core.drain_all();
+            }
+        }
+    }
+
+    pub fn set_fatal(&self) {
+        let mut core = self.core.lock();
+        core.fatal = true;
+        let csts = core.r32(reg::CSTS);
+        core.w32(reg::CSTS, csts | reg::CSTS_CFS);
+    }
+
+    pub fn set_stall(&self, sqid: u16, stall: bool) {
+        let mut core = self.core.lock();
+        if stall {
+            core.stalled.insert(sqid);
+        } else if core.stalled.remove(&sqid) && core.en {
+            core.drain(sqid);
+        }
+    }
+
+    pub fn set_abort_fail(&self, fail: bool) {
+        self.core.lock().no_abort = fail;
+    }
+
+    pub fn fail_io(&self, st: u16, count: usize) {
+        let mut core = self.core.lock();
+        for _ in 0..count {
+            core.fails.push_back(st);
+        }
+    }
+
+    pub fn fail_admin(&self, opc: u8, st: u16, count: usize) {
+        let mut core = self.core.lock();
+        for _ in 0..count {
+            core.adm_fails.push_back((opc, st));
+        }
+    }
+
+    pub fn irqs(&self, vector: u16) -> u64 {
+        return self.core.lock().irqs.get(&vector).copied().unwrap_or(0);
+    }
+
+    pub fn sq_prio(&self, sqid: u16) -> Option<u8> {
+        return self.core.lock().sqs.get(&sqid).map(|sq| sq.prio);
+    }
+
+    pub fn sq_doorbells(&self, sqid: u16) -> u64 {
+        return self.core.lock().sq_dbs.get(&sqid).copied().unwrap_or(0);
+    }
+
+    pub fn resets(&self) -> u64 {
+        return self.core.lock().resets;
+    }
+
+    pub fn ns_data(&self, nsid: u32) -> Option<Vec<u8>> {
+        return self.core.lock().ns(nsid).map(|ns| ns.data.clone());
+    }
+}
+
+impl Mmio for Emu {
+    fn read32(&self, off: usize) -> u32 {
+        return self.core.lock().r32(off);
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        self.core.lock().mmio_write(off, val);
+    }
+}
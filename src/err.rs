@@ -1,14 +1,285 @@
-use core::result::Result as CoreResult;
+use core::{fmt, result::Result as CoreResult};
 
 #[derive(Debug, Clone, Copy)]
 pub enum NVMeError {
     Timeout,
+    Aborted,
+    Reset,
     OoRam,
     InvQp,
     FullQp,
-    CmdFail(u16),
+    CmdFail(Status),
     IoError,
-    InvBuf
+    InvBuf,
+    InvCfg,
+    NonContig,
+    Unsupported
 }
 
 pub type Result<T> = CoreResult<T, NVMeError>;
+
+impl fmt::Display for NVMeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            NVMeError::Timeout => write!(f, "command timed out"),
+            NVMeError::Aborted => write!(f, "command aborted after timeout"),
+            NVMeError::Reset => write!(f, "controller reset while command was outstanding"),
+            NVMeError::OoRam => write!(f, "out of DMA memory"),
+            NVMeError::InvQp => write!(f, "invalid queue"),
+            NVMeError::FullQp => write!(f, "queue full"),
+            NVMeError::CmdFail(st) => write!(f, "command failed: {}", st),
+            NVMeError::IoError => write!(f, "I/O error"),
+            NVMeError::InvBuf => write!(f, "invalid buffer"),
+            NVMeError::InvCfg => write!(f, "configuration not supported by controller"),
+            NVMeError::NonContig => write!(f, "memory is not physically contiguous"),
+            NVMeError::Unsupported => write!(f, "command not supported by controller")
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sct {
+    Generic,
+    CmdSpecific,
+    Media,
+    Path,
+    Vendor,
+    Rsvd(u8)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Success,
+    InvalidOpcode,
+    InvalidField,
+    CidConflict,
+    DataXferError,
+    PowerLoss,
+    Internal,
+    AbortRequested,
+    AbortSqDeleted,
+    AbortFusedFail,
+    AbortFusedMissing,
+    InvalidNs,
+    CmdSeqError,
+    InvalidSglDesc,
+    InvalidSglCount,
+    InvalidSglLength,
+    InvalidSglType,
+    InvalidPrpOffset,
+    OpDenied,
+    SanitizeInProgress,
+    NsWriteProtected,
+    CmdInterrupted,
+    TransientTransport,
+    LbaOutOfRange,
+    CapacityExceeded,
+    NsNotReady,
+    ReservationConflict,
+    FormatInProgress,
+    InvalidCq,
+    InvalidQid,
+    InvalidQsize,
+    AbortLimit,
+    AsyncEventLimit,
+    InvalidFwSlot,
+    InvalidFwImage,
+    InvalidVector,
+    InvalidLogPage,
+    InvalidFormat,
+    InvalidQdel,
+    FeatNotSaveable,
+    FeatNotChangeable,
+    NsInsufficientCap,
+    NsAlreadyAttached,
+    NsNotAttached,
+    ConflictingAttrs,
+    InvalidProtInfo,
+    WriteToRoRange,
+    WriteFault,
+    UnrecoveredRead,
+    GuardCheck,
+    AppTagCheck,
+    RefTagCheck,
+    CompareFailure,
+    AccessDenied,
+    DeallocatedLba,
+    PathInternal,
+    AnaPersistentLoss,
+    AnaInaccessible,
+    AnaTransition,
+    CtrlPathError,
+    HostPathError,
+    AbortedByHost,
+    Other
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub sct: Sct,
+    pub sc: u8,
+    pub crd: u8,
+    pub more: bool,
+    pub dnr: bool
+}
+
+impl Status {
+    pub fn from_raw(sf: u16) -> Self {
+        let sct = match (sf >> 8) & 0x7 {
+            0 => Sct::Generic,
+            1 => Sct::CmdSpecific,
+            2 => Sct::Media,
+            3 => Sct::Path,
+            7 => Sct::Vendor,
+            n => Sct::Rsvd(n as u8)
+        };
+
+        return Self {
+            sct,
+            sc: sf as u8,
+            crd: ((sf >> 11) & 0x3) as u8,
+            more: sf & (1 << 13) != 0,
+            dnr: sf & (1 << 14) != 0
+        };
+    }
+
+    pub fn raw(&self) -> u16 {
+        let sct = match self.sct {
+            Sct::Generic => 0,
+            Sct::CmdSpecific => 1,
+            Sct::Media => 2,
+            Sct::Path => 3,
+            Sct::Vendor => 7,
+            Sct::Rsvd(n) => n as u16 & 0x7
+        };
+        return self.sc as u16
+            | sct << 8
+            | (self.crd as u16 & 0x3) << 11
+            | (self.more as u16) << 13
+            | (self.dnr as u16) << 14;
+    }
+
+    pub fn is_ok(&self) -> bool {
+        return self.sct == Sct::Generic && self.sc == 0;
+    }
+
+    pub fn code(&self) -> StatusCode {
+        return self.describe().0;
+    }
+
+    pub fn is_transient(&self) -> bool {
+        if self.dnr {
+            return false;
+        }
+        return match self.code() {
+            StatusCode::NsNotReady | StatusCode::AbortSqDeleted | StatusCode::CmdInterrupted => true,
+            StatusCode::AbortedByHost => false,
+            _ => self.sct == Sct::Path
+        };
+    }
+
+    fn describe(&self) -> (StatusCode, &'static str) {
+        use StatusCode::*;
+        return match (self.sct, self.sc) {
+            (Sct::Generic, 0x00) => (Success, "successful completion"),
+            (Sct::Generic, 0x01) => (InvalidOpcode, "invalid command opcode"),
+            (Sct::Generic, 0x02) => (InvalidField, "invalid field in command"),
+            (Sct::Generic, 0x03) => (CidConflict, "command ID conflict"),
+            (Sct::Generic, 0x04) => (DataXferError, "data transfer error"),
+            (Sct::Generic, 0x05) => (PowerLoss, "aborted due to power loss notification"),
+            (Sct::Generic, 0x06) => (Internal, "internal error"),
+            (Sct::Generic, 0x07) => (AbortRequested, "command abort requested"),
+            (Sct::Generic, 0x08) => (AbortSqDeleted, "aborted due to SQ deletion"),
+            (Sct::Generic, 0x09) => (AbortFusedFail, "aborted due to failed fused command"),
+            (Sct::Generic, 0x0A) => (AbortFusedMissing, "aborted due to missing fused command"),
+            (Sct::Generic, 0x0B) => (InvalidNs, "invalid namespace or format"),
+            (Sct::Generic, 0x0C) => (CmdSeqError, "command sequence error"),
+            (Sct::Generic, 0x0D) => (InvalidSglDesc, "invalid SGL segment descriptor"),
+            (Sct::Generic, 0x0E) => (InvalidSglCount, "invalid number of SGL descriptors"),
+            (Sct::Generic, 0x0F) => (InvalidSglLength, "data SGL length invalid"),
+            (Sct::Generic, 0x11) => (InvalidSglType, "SGL descriptor type invalid"),
+            (Sct::Generic, 0x13) => (InvalidPrpOffset, "PRP offset invalid"),
+            (Sct::Generic, 0x15) => (OpDenied, "operation denied"),
+            (Sct::Generic, 0x1D) => (SanitizeInProgress, "sanitize in progress"),
+            (Sct::Generic, 0x20) => (NsWriteProtected, "namespace is write protected"),
+            (Sct::Generic, 0x21) => (CmdInterrupted, "command interrupted"),
+            (Sct::Generic, 0x22) => (TransientTransport, "transient transport error"),
+            (Sct::Generic, 0x80) => (LbaOutOfRange, "LBA out of range"),
+            (Sct::Generic, 0x81) => (CapacityExceeded, "capacity exceeded"),
+            (Sct::Generic, 0x82) => (NsNotReady, "namespace not ready"),
+            (Sct::Generic, 0x83) => (ReservationConflict, "reservation conflict"),
+            (Sct::Generic, 0x84) => (FormatInProgress, "format in progress"),
+            (Sct::CmdSpecific, 0x00) => (InvalidCq, "completion queue invalid"),
+            (Sct::CmdSpecific, 0x01) => (InvalidQid, "invalid queue identifier"),
+            (Sct::CmdSpecific, 0x02) => (InvalidQsize, "invalid queue size"),
+            (Sct::CmdSpecific, 0x03) => (AbortLimit, "abort command limit exceeded"),
+            (Sct::CmdSpecific, 0x05) => (AsyncEventLimit, "asynchronous event request limit exceeded"),
+            (Sct::CmdSpecific, 0x06) => (InvalidFwSlot, "invalid firmware slot"),
+            (Sct::CmdSpecific, 0x07) => (InvalidFwImage, "invalid firmware image"),
+            (Sct::CmdSpecific, 0x08) => (InvalidVector, "invalid interrupt vector"),
+            (Sct::CmdSpecific, 0x09) => (InvalidLogPage, "invalid log page"),
+            (Sct::CmdSpecific, 0x0A) => (InvalidFormat, "invalid format"),
+            (Sct::CmdSpecific, 0x0C) => (InvalidQdel, "invalid queue deletion"),
+            (Sct::CmdSpecific, 0x0D) => (FeatNotSaveable, "feature identifier not saveable"),
+            (Sct::CmdSpecific, 0x0E) => (FeatNotChangeable, "feature not changeable"),
+            (Sct::CmdSpecific, 0x15) => (NsInsufficientCap, "namespace insufficient capacity"),
+            (Sct::CmdSpecific, 0x18) => (NsAlreadyAttached, "namespace already attached"),
+            (Sct::CmdSpecific, 0x1A) => (NsNotAttached, "namespace not attached"),
+            (Sct::CmdSpecific, 0x80) => (ConflictingAttrs, "conflicting attributes"),
+            (Sct::CmdSpecific, 0x81) => (InvalidProtInfo, "invalid protection information"),
+            (Sct::CmdSpecific, 0x82) => (WriteToRoRange, "attempted write to read only range"),
+            (Sct::Media, 0x80) => (WriteFault, "write fault"),
+            (Sct::Media, 0x81) => (UnrecoveredRead, "unrecovered read error"),
+            (Sct::Media, 0x82) => (GuardCheck, "end-to-end guard check error"),
+            (Sct::Media, 0x83) => (AppTagCheck, "end-to-end application tag check error"),
+            (Sct::Media, 0x84) => (RefTagCheck, "end-to-end reference tag check error"),
+            (Sct::Media, 0x85) => (CompareFailure, "compare failure"),
+            (Sct::Media, 0x86) => (AccessDenied, "access denied"),
+            (Sct::Media, 0x87) => (DeallocatedLba, "deallocated or unwritten logical block"),
+            (Sct::Path, 0x00) => (PathInternal, "internal path error"),
+            (Sct::Path, 0x01) => (AnaPersistentLoss, "asymmetric access persistent loss"),
+            (Sct::Path, 0x02) => (AnaInaccessible, "asymmetric access inaccessible"),
+            (Sct::Path, 0x03) => (AnaTransition, "asymmetric access transition"),
+            (Sct::Path, 0x60) => (CtrlPathError, "controller pathing error"),
+            (Sct::Path, 0x70) => (HostPathError, "host pathing error"),
+            (Sct::Path, 0x71) => (AbortedByHost, "command aborted by host"),
+            _ => (Other, "unknown status")
+        };
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (sct {:?}, sc {:#04x}", self.describe().1, self.sct, self.sc)?;
+        if self.crd != 0 {
+            write!(f, ", crd {}", self.crd)?;
+        }
+        if self.more {
+            write!(f, ", more")?;
+        }
+        if self.dnr {
+            write!(f, ", dnr")?;
+        }
+        return write!(f, ")");
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LbaError {
+    pub lba: u64,
+    pub err: NVMeError
+}
+
+impl From<LbaError> for NVMeError {
+    fn from(e: LbaError) -> Self {
+        return e.err;
+    }
+}
+
+impl fmt::Display for LbaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{} at LBA {}", self.err, self.lba);
+    }
+}
+
+pub type LbaResult<T> = CoreResult<T, LbaError>;
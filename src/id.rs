@@ -1,3 +1,10 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeKind {
+    BlockErase,
+    Overwrite,
+    CryptoErase
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct CtrlId {
@@ -183,6 +190,51 @@ impl CtrlId {
         return Some(pg_size * (1 << self.mdts));
     }
 
+    pub fn sgl_align(&self) -> Option<usize> {
+        return match self.sgls & 0x3 {
+            1 => Some(1),
+            2 => Some(4),
+            _ => None
+        };
+    }
+
+    pub fn sgl_bit_bucket(&self) -> bool {
+        return self.sgls & (1 << 16) != 0;
+    }
+
+    pub fn supports_compare(&self) -> bool {
+        return self.oncs & ONCS_COMPARE != 0;
+    }
+
+    pub fn supports_dsm(&self) -> bool {
+        return self.oncs & ONCS_DSM != 0;
+    }
+
+    pub fn supports_write_zeroes(&self) -> bool {
+        return self.oncs & ONCS_WR_ZERO != 0;
+    }
+
+    pub fn supports_verify(&self) -> bool {
+        return self.oncs & ONCS_VERIFY != 0;
+    }
+
+    pub fn supports_ns_mgmt(&self) -> bool {
+        return self.oacs & (1 << 3) != 0;
+    }
+
+    pub fn supports_sanitize(&self, kind: SanitizeKind) -> bool {
+        let bit = match kind {
+            SanitizeKind::CryptoErase => 0,
+            SanitizeKind::BlockErase => 1,
+            SanitizeKind::Overwrite => 2
+        };
+        return self.sanicap & (1 << bit) != 0;
+    }
+
+    pub fn has_vwc(&self) -> bool {
+        return self.vwc & 1 != 0;
+    }
+
     pub fn version(&self) -> (u8, u8, u8) {
         let major = ((self.ver >> 16) & 0xFF) as u8;
         let minor = ((self.ver >> 8) & 0xFF) as u8;
@@ -326,6 +378,64 @@ pub const FT_TSTAMP: u8 = 0x0E;
 pub const FT_KEEPALV: u8 = 0x0F;
 pub const FT_THERM: u8 = 0x10;
 pub const FT_NOP_PS: u8 = 0x11;
+pub const FT_HOST_BEHAV: u8 = 0x16;
+
+pub const ONCS_COMPARE: u16 = 1 << 0;
+pub const ONCS_DSM: u16 = 1 << 2;
+pub const ONCS_WR_ZERO: u16 = 1 << 3;
+pub const ONCS_VERIFY: u16 = 1 << 7;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArbConfig {
+    pub value: u32
+}
+
+impl ArbConfig {
+    pub fn new() -> Self {
+        return Self { value: 0 };
+    }
+
+    pub fn from_raw(value: u32) -> Self {
+        return Self { value };
+    }
+
+    pub fn burst(&self) -> u8 {
+        return (self.value & 0x7) as u8;
+    }
+
+    pub fn low(&self) -> u8 {
+        return (self.value >> 8) as u8;
+    }
+
+    pub fn mid(&self) -> u8 {
+        return (self.value >> 16) as u8;
+    }
+
+    pub fn high(&self) -> u8 {
+        return (self.value >> 24) as u8;
+    }
+
+    pub fn set_burst(&mut self, ab: u8) -> &mut Self {
+        self.value = (self.value & !0x7) | (ab as u32 & 0x7);
+        return self;
+    }
+
+    pub fn set_low(&mut self, w: u8) -> &mut Self {
+        self.value = (self.value & !(0xFF << 8)) | (w as u32) << 8;
+        return self;
+    }
+
+    pub fn set_mid(&mut self, w: u8) -> &mut Self {
+        self.value = (self.value & !(0xFF << 16)) | (w as u32) << 16;
+        return self;
+    }
+
+    pub fn set_high(&mut self, w: u8) -> &mut Self {
+        self.value = (self.value & !(0xFF << 24)) | (w as u32) << 24;
+        return self;
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -362,7 +472,7 @@ pub struct AsyncEventInfo {
 
 impl AsyncEventInfo {
     pub fn evt_type(&self) -> u8 {
-        return ((self.dw0 >> 0) & 0x7) as u8;
+        return (self.dw0 & 0x7) as u8;
     }
 
     pub fn evt_info(&self) -> u8 {
@@ -1,23 +1,41 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
 mod cmd;
 mod ctrl;
 mod dev;
+#[cfg(feature = "std")]
+mod emu;
 mod err;
+#[allow(dead_code)]
 mod id;
 mod ns;
 mod queue;
 mod ram;
+#[allow(dead_code)]
 mod reg;
+mod time;
 
 pub use crate::{
-    ctrl::Ctrl,
+    cmd::{Cmd, DataDir, Passthru, Sqe, FUSE_FIRST, FUSE_NONE, FUSE_SECOND, PSDT_PRP, PSDT_SGL, PSDT_SGL_MPTR},
+    ctrl::{ArbMode, CmdClass, CmdSet, Ctrl, CtrlConfig, DmaMap, IoQueuePair, RetryPolicy},
     dev::NVMeDev,
-    err::{NVMeError, Result},
-    id::{CtrlId, LbaFormat, NsId, PwrStDesc, LogSmart, LogErr},
+    err::{LbaError, LbaResult, NVMeError, Result, Sct, Status, StatusCode},
+    id::{
+        ArbConfig, CtrlId, LbaFormat, LogErr, LogSmart, NsId, PwrStDesc, SanitizeKind,
+        ONCS_COMPARE, ONCS_DSM, ONCS_VERIFY, ONCS_WR_ZERO
+    },
     ns::Ns,
-    queue::{Cq, Sq},
-    ram::Dma
+    queue::{Completion, Cq, Cqe, QPrio, Queue, Sq},
+    ram::{
+        build_prp, build_sgl, sgl_descs, BouncePool, BounceStats, Dma, DmaPool, PrpList, SglDesc,
+        SGL_BIT_BUCKET, SGL_DATA, SGL_LAST_SEG, SGL_SEGMENT
+    },
+    reg::Mmio,
+    time::{Clock, PollClock}
 };
+#[cfg(feature = "std")]
+pub use crate::{emu::{Emu, EmuCfg}, ram::HeapDma, time::StdClock};
@@ -1,16 +1,16 @@
-use crate::{cmd::Cmd, id::NsId, ram::build_prp, Ctrl, Dma, NVMeError, Result};
-use alloc::sync::Arc;
+use crate::{cmd::Cmd, ctrl::DmaMap, id::NsId, Ctrl, Dma, DmaPool, IoQueuePair, LbaError, LbaResult, Mmio, NVMeError, Result};
+use alloc::{sync::Arc, vec::Vec};
 
-pub struct Ns<A: Dma> {
-    ctrl: Arc<Ctrl<A>>,
+pub struct Ns<A: Dma, M: Mmio = usize> {
+    ctrl: Arc<Ctrl<A, M>>,
     nsid: u32,
     blk_sz: usize,
     blk_cnt: u64
 }
 
-impl<A: Dma> Ns<A> {
-    pub fn new(ctrl: Arc<Ctrl<A>>, nsid: u32) -> Result<Self> {
-        let buffer = unsafe { ctrl.alloc().alloc(4096) };
+impl<A: Dma, M: Mmio> Ns<A, M> {
+    pub fn new(ctrl: Arc<Ctrl<A, M>>, nsid: u32) -> Result<Self> {
+        let buffer = unsafe { ctrl.pool().alloc(4096) };
         if buffer == 0 {
             return Err(NVMeError::OoRam);
         }
@@ -19,9 +19,12 @@ impl<A: Dma> Ns<A> {
             (buffer as *mut u8).write_bytes(0, 4096);
         }
 
-        let buffer_phys = ctrl.alloc().virt_to_phys(buffer) as u64;
+        let buffer_phys = ctrl.pool().virt_to_phys(buffer) as u64;
         let cmd = Cmd::id_ns(nsid, buffer_phys);
-        ctrl.admin_cmd(&cmd)?;
+        if let Err(e) = ctrl.admin_cmd(&cmd) {
+            unsafe { ctrl.pool().free(buffer, 4096); }
+            return Err(e);
+        }
 
         unsafe {
             let ns_id = &*(buffer as *const NsId);
@@ -29,7 +32,7 @@ impl<A: Dma> Ns<A> {
             let blk_sz = ns_id.lba_size();
             let blk_cnt = ns_id.nsze;
 
-            ctrl.alloc().free(buffer, 4096);
+            ctrl.pool().free(buffer, 4096);
 
             return Ok(Self {
                 ctrl,
@@ -52,40 +55,71 @@ impl<A: Dma> Ns<A> {
         return self.blk_cnt;
     }
 
-    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<()> {
-        let nlb = (buf.len() / self.blk_sz) as u16;
-        let (prp1, prp2, prp_list) = build_prp(
-            self.ctrl.alloc(),
-            buf.as_ptr() as usize,
-            buf.len()
-        )?;
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> LbaResult<()> {
+        return self.xfer(None, lba, buf.as_ptr() as usize, buf.len(), Cmd::read, true);
+    }
+
+    pub fn write(&self, lba: u64, buf: &[u8]) -> LbaResult<()> {
+        return self.xfer(None, lba, buf.as_ptr() as usize, buf.len(), Cmd::write, false);
+    }
 
-        let cmd = Cmd::read(self.nsid, lba, nlb, prp1, prp2);
-        let res = self.ctrl.io_cmd(&cmd);
+    pub fn read_qp(&self, qp: &mut IoQueuePair<A, M>, lba: u64, buf: &mut [u8]) -> LbaResult<()> {
+        return self.xfer(Some(qp), lba, buf.as_ptr() as usize, buf.len(), Cmd::read, true);
+    }
+
+    pub fn write_qp(&self, qp: &mut IoQueuePair<A, M>, lba: u64, buf: &[u8]) -> LbaResult<()> {
+        return self.xfer(Some(qp), lba, buf.as_ptr() as usize, buf.len(), Cmd::write, false);
+    }
 
-        if let Some(list) = prp_list {
-            list.free(self.ctrl.alloc());
+    pub fn compare_qp(&self, qp: &mut IoQueuePair<A, M>, lba: u64, buf: &[u8]) -> LbaResult<()> {
+        if !self.ctrl.supports_compare() {
+            return Err(LbaError { lba, err: NVMeError::Unsupported });
         }
+        return self.xfer(Some(qp), lba, buf.as_ptr() as usize, buf.len(), Cmd::cmp, false);
+    }
 
-        return res;
+    pub fn flush_qp(&self, qp: &mut IoQueuePair<A, M>) -> Result<()> {
+        if !Arc::ptr_eq(qp.ctrl(), &self.ctrl) {
+            return Err(NVMeError::InvQp);
+        }
+        return qp.io_cmd(&Cmd::flush(self.nsid));
     }
 
-    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<()> {
-        let nlb = (buf.len() / self.blk_sz) as u16;
-        let (prp1, prp2, prp_list) = build_prp(
-            self.ctrl.alloc(),
-            buf.as_ptr() as usize,
-            buf.len()
-        )?;
+    pub fn trim_qp(&self, qp: &mut IoQueuePair<A, M>, lba: u64, blocks: u64) -> Result<()> {
+        if !Arc::ptr_eq(qp.ctrl(), &self.ctrl) {
+            return Err(NVMeError::InvQp);
+        }
+        return self.dsm(Some(qp), lba, blocks);
+    }
 
-        let cmd = Cmd::write(self.nsid, lba, nlb, prp1, prp2);
-        let res = self.ctrl.io_cmd(&cmd);
+    pub fn write_zeroes_qp(&self, qp: &mut IoQueuePair<A, M>, lba: u64, blocks: u16) -> Result<()> {
+        if !Arc::ptr_eq(qp.ctrl(), &self.ctrl) {
+            return Err(NVMeError::InvQp);
+        }
+        if blocks == 0 {
+            return Ok(());
+        }
+        if !self.ctrl.supports_write_zeroes() {
+            return Err(NVMeError::Unsupported);
+        }
+        return qp.io_cmd(&Cmd::wr_zero(self.nsid, lba, blocks as u32));
+    }
 
-        if let Some(list) = prp_list {
-            list.free(self.ctrl.alloc());
+    pub fn verify_qp(&self, qp: &mut IoQueuePair<A, M>, lba: u64, blocks: u64) -> LbaResult<()> {
+        if !Arc::ptr_eq(qp.ctrl(), &self.ctrl) {
+            return Err(LbaError { lba, err: NVMeError::InvQp });
         }
+        return self.check(Some(qp), lba, blocks);
+    }
 
-        return res;
+    pub fn read_many(&self, reqs: &mut [(u64, &mut [u8])]) -> LbaResult<()> {
+        let reqs = reqs.iter().map(|(lba, buf)| (*lba, buf.as_ptr() as usize, buf.len()));
+        return self.batch(reqs, Cmd::read, true);
+    }
+
+    pub fn write_many(&self, reqs: &[(u64, &[u8])]) -> LbaResult<()> {
+        let reqs = reqs.iter().map(|(lba, buf)| (*lba, buf.as_ptr() as usize, buf.len()));
+        return self.batch(reqs, Cmd::write, false);
     }
 
     pub fn flush(&self) -> Result<()> {
@@ -94,6 +128,10 @@ impl<A: Dma> Ns<A> {
     }
 
     pub fn trim(&self, lba: u64, blocks: u64) -> Result<()> {
+        return self.dsm(None, lba, blocks);
+    }
+
+    fn dsm(&self, qp: Option<&mut IoQueuePair<A, M>>, lba: u64, blocks: u64) -> Result<()> {
         #[repr(C, packed)]
         struct DsmRange {
             context_attr: u32,
@@ -101,7 +139,13 @@ impl<A: Dma> Ns<A> {
             slba: u64
         }
 
-        let range_buf = unsafe { self.ctrl.alloc().alloc(16) };
+        if !self.ctrl.supports_dsm() {
+            return Err(NVMeError::Unsupported);
+        }
+
+        let prp = qp.as_ref().map(|qp| qp.pool().clone());
+        let pool = prp.as_deref().unwrap_or(self.ctrl.pool());
+        let range_buf = unsafe { pool.alloc(16) };
         if range_buf == 0 {
             return Err(NVMeError::OoRam);
         }
@@ -116,39 +160,163 @@ impl<A: Dma> Ns<A> {
             (range_buf as *mut DsmRange).write_volatile(range);
         }
 
-        let range_phys = self.ctrl.alloc().virt_to_phys(range_buf) as u64;
+        let range_phys = pool.virt_to_phys(range_buf) as u64;
         let cmd = Cmd::dset_mgmt(self.nsid, 0, range_phys, 0x4);
-        let res = self.ctrl.io_cmd(&cmd);
+        let res = match qp {
+            Some(qp) => qp.io_cmd(&cmd),
+            None => self.ctrl.io_cmd(&cmd)
+        };
 
-        unsafe { self.ctrl.alloc().free(range_buf, 16); }
+        unsafe { pool.free(range_buf, 16); }
         return res;
     }
 
     pub fn write_zeroes(&self, lba: u64, blocks: u16) -> Result<()> {
-        let cmd = Cmd::wr_zero(self.nsid, lba, blocks);
+        if blocks == 0 {
+            return Ok(());
+        }
+        if !self.ctrl.supports_write_zeroes() {
+            return Err(NVMeError::Unsupported);
+        }
+
+        let cmd = Cmd::wr_zero(self.nsid, lba, blocks as u32);
         return self.ctrl.io_cmd(&cmd);
     }
 
-    pub fn verify(&self, lba: u64, blocks: u16) -> Result<()> {
-        let cmd = Cmd::verify(self.nsid, lba, blocks);
-        return self.ctrl.io_cmd(&cmd);
+    pub fn verify(&self, lba: u64, blocks: u64) -> LbaResult<()> {
+        return self.check(None, lba, blocks);
     }
 
-    pub fn compare(&self, lba: u64, buf: &[u8]) -> Result<()> {
-        let nlb = (buf.len() / self.blk_sz) as u16;
-        let (prp1, prp2, prp_list) = build_prp(
-            self.ctrl.alloc(),
-            buf.as_ptr() as usize,
-            buf.len()
-        )?;
+    fn check(&self, qp: Option<&mut IoQueuePair<A, M>>, lba: u64, blocks: u64) -> LbaResult<()> {
+        if !self.ctrl.supports_verify() {
+            return Err(LbaError { lba, err: NVMeError::Unsupported });
+        }
 
-        let cmd = Cmd::cmp(self.nsid, lba, nlb, prp1, prp2);
-        let res = self.ctrl.io_cmd(&cmd);
+        let max = self.max_blks();
+        let mk = |_: &DmaPool<A>, i: usize| {
+            let off = i as u64 * max;
+            let n = (blocks - off).min(max);
+            return Ok((Cmd::verify(self.nsid, lba + off, n as u32), None));
+        };
+
+        let n = blocks.div_ceil(max) as usize;
+        let res = match qp {
+            Some(qp) => qp.io_chunks(n, false, mk),
+            None => self.ctrl.io_chunks(n, false, mk)
+        };
+        return res.map_err(|(i, err)| LbaError { lba: lba + i as u64 * max, err });
+    }
 
-        if let Some(list) = prp_list {
-            list.free(self.ctrl.alloc());
+    pub fn compare(&self, lba: u64, buf: &[u8]) -> LbaResult<()> {
+        if !self.ctrl.supports_compare() {
+            return Err(LbaError { lba, err: NVMeError::Unsupported });
         }
+        return self.xfer(None, lba, buf.as_ptr() as usize, buf.len(), Cmd::cmp, false);
+    }
+
+    fn map(
+        &self,
+        pool: &DmaPool<A>,
+        lba: u64,
+        buf: usize,
+        n: usize,
+        mk: fn(u32, u64, u32, u64, u64) -> Cmd,
+        rd: bool
+    ) -> Result<(Cmd, DmaMap)> {
+        let nlb = (n / self.blk_sz) as u32;
+        let (dp1, dp2, psdt, map) = self.ctrl.map_data_in(pool, buf, n, rd)?;
+        return Ok((mk(self.nsid, lba, nlb, dp1, dp2).with_psdt(psdt), map));
+    }
+
+    fn unmap(&self, pool: &DmaPool<A>, map: DmaMap, copy: bool) {
+        self.ctrl.unmap_data_in(pool, map, copy);
+    }
+
+    fn max_blks(&self) -> u64 {
+        let mts = self.ctrl.data().mts / self.blk_sz;
+        return (mts as u64).clamp(1, 65536);
+    }
 
+    fn batch(
+        &self,
+        reqs: impl Iterator<Item = (u64, usize, usize)>,
+        mk: fn(u32, u64, u32, u64, u64) -> Cmd,
+        rd: bool
+    ) -> LbaResult<()> {
+        let chunk = self.max_blks() as usize * self.blk_sz;
+        let pool = self.ctrl.pool();
+
+        let mut cmds = Vec::new();
+        let mut maps = Vec::new();
+        let mut lbas = Vec::new();
+        let mut failed = None;
+
+        'reqs: for (lba, buf, len) in reqs {
+            if len == 0 || !len.is_multiple_of(self.blk_sz) {
+                failed = Some(LbaError { lba, err: NVMeError::InvBuf });
+                break;
+            }
+
+            let mut off = 0;
+            while off < len {
+                let n = (len - off).min(chunk);
+                let clba = lba + (off / self.blk_sz) as u64;
+
+                match self.map(pool, clba, buf + off, n, mk, rd) {
+                    Ok((cmd, map)) => {
+                        cmds.push(cmd);
+                        maps.push(map);
+                        lbas.push(clba);
+                    }
+                    Err(err) => {
+                        failed = Some(LbaError { lba: clba, err });
+                        break 'reqs;
+                    }
+                }
+                off += n;
+            }
+        }
+
+        let res = match failed {
+            Some(e) => Err(e),
+            None => self.ctrl.io_batch(&cmds).map_err(|(i, err)| LbaError { lba: lbas[i], err })
+        };
+
+        for map in maps {
+            self.unmap(pool, map, rd && res.is_ok());
+        }
         return res;
     }
+
+    fn xfer(
+        &self,
+        qp: Option<&mut IoQueuePair<A, M>>,
+        lba: u64,
+        buf: usize,
+        len: usize,
+        mk: fn(u32, u64, u32, u64, u64) -> Cmd,
+        rd: bool
+    ) -> LbaResult<()> {
+        if let Some(qp) = qp.as_ref() && !Arc::ptr_eq(qp.ctrl(), &self.ctrl) {
+            return Err(LbaError { lba, err: NVMeError::InvQp });
+        }
+        if len == 0 || !len.is_multiple_of(self.blk_sz) {
+            return Err(LbaError { lba, err: NVMeError::InvBuf });
+        }
+
+        let chunk = self.max_blks() as usize * self.blk_sz;
+        let lba_at = |i: usize| lba + (i * chunk / self.blk_sz) as u64;
+        let map = |pool: &DmaPool<A>, i: usize| {
+            let off = i * chunk;
+            let n = (len - off).min(chunk);
+            return self.map(pool, lba_at(i), buf + off, n, mk, rd).map(|(cmd, map)| (cmd, Some(map)));
+        };
+
+        let n = len.div_ceil(chunk);
+        let res = match qp {
+            Some(qp) => qp.io_chunks(n, rd, map),
+            None => self.ctrl.io_chunks(n, rd, map)
+        };
+        return res.map_err(|(i, err)| LbaError { lba: lba_at(i), err });
+    }
 }
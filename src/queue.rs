@@ -1,5 +1,15 @@
-use crate::{cmd::{Cmd, Sqe}, reg, Dma, NVMeError, Result};
-use core::{marker::PhantomData, sync::atomic::{AtomicU16, AtomicU8, Ordering}};
+use crate::{
+    cmd::{Cmd, Sqe}, err::Status, ram::PrpList, reg::{self, CstsReg}, time::Deadline, Clock, Dma, Mmio, NVMeError, Result
+};
+use core::{
+    future::Future,
+    hint::spin_loop,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering},
+    task::{Context, Poll, Waker}
+};
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, vec::Vec};
+use spin::Mutex;
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -17,79 +27,316 @@ impl Cqe {
         return (self.sf & 1) != 0;
     }
 
-    pub fn status(&self) -> u16 {
-        return (self.sf >> 1) & 0x7FF;
+    pub fn status(&self) -> Status {
+        return Status::from_raw(self.sf >> 1);
     }
 
     pub fn ok(&self) -> bool {
-        return self.status() == 0;
+        return self.status().is_ok();
+    }
+
+    pub fn into_result(self) -> Result<Cqe> {
+        if !self.ok() {
+            return Err(NVMeError::CmdFail(self.status()));
+        }
+        return Ok(self);
     }
 }
 
-pub struct Sq<A: Dma> {
-    qid: u16,
-    addr: usize,
+enum Slot {
+    Free,
+    Wait(Option<Waker>),
+    Done(Cqe),
+    Lost
+}
+
+struct CidTab {
+    slots: Vec<Slot>,
+    next: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QPrio {
+    Urgent = 0,
+    High = 1,
+    Medium = 2,
+    Low = 3
+}
+
+struct Ring {
+    pages: Vec<usize>,
+    pg: usize,
+    bytes: usize,
     phys: u64,
-    size: usize,
-    tail: AtomicU16,
-    cid: AtomicU16,
-    pending: AtomicU16,
-    _alloc: PhantomData<A>
+    list: Option<PrpList>
 }
 
-impl<A: Dma> Sq<A> {
-    pub fn new(qid: u16, size: usize, alloc: &A) -> Result<Self> {
-        let bytes = size * 64;
-        let addr = unsafe { alloc.alloc(bytes) };
-        if addr == 0 {
+impl Ring {
+    fn new<A: Dma>(alloc: &A, bytes: usize, pg: usize, contig: bool) -> Result<Self> {
+        let bytes = bytes.next_multiple_of(pg);
+        let n = bytes / pg;
+
+        if contig || n == 1 {
+            let addr = unsafe { alloc.alloc(bytes) };
+            if addr == 0 {
+                return Err(NVMeError::OoRam);
+            }
+
+            let phys = alloc.virt_to_phys(addr) as u64;
+            let ring = Self { pages: (0..n).map(|i| addr + i * pg).collect(), pg, bytes, phys, list: None };
+            if phys as usize & (pg - 1) != 0 {
+                ring.free(alloc);
+                return Err(NVMeError::InvBuf);
+            }
+            if (1..n).any(|i| alloc.virt_to_phys(addr + i * pg) as u64 != phys + (i * pg) as u64) {
+                ring.free(alloc);
+                return Err(NVMeError::NonContig);
+            }
+
+            unsafe { (addr as *mut u8).write_bytes(0, bytes); }
+            return Ok(ring);
+        }
+
+        let list_sz = (n * 8).next_multiple_of(pg);
+        let list_va = unsafe { alloc.alloc(list_sz) };
+        if list_va == 0 {
             return Err(NVMeError::OoRam);
         }
 
-        unsafe {
-            (addr as *mut u8).write_bytes(0, bytes);
+        let list_pa = alloc.virt_to_phys(list_va);
+        let mut ring = Self {
+            pages: Vec::with_capacity(n),
+            pg,
+            bytes: pg,
+            phys: list_pa as u64,
+            list: Some(PrpList { addr: list_va, sz: list_sz })
+        };
+        if list_pa & (pg - 1) != 0 {
+            ring.free(alloc);
+            return Err(NVMeError::InvBuf);
+        }
+        if (pg..list_sz).step_by(pg).any(|off| alloc.virt_to_phys(list_va + off) != list_pa + off) {
+            ring.free(alloc);
+            return Err(NVMeError::NonContig);
         }
 
-        let phys = alloc.virt_to_phys(addr) as u64;
+        for i in 0..n {
+            let va = unsafe { alloc.alloc(pg) };
+            if va == 0 {
+                ring.free(alloc);
+                return Err(NVMeError::OoRam);
+            }
+            ring.pages.push(va);
+
+            let pa = alloc.virt_to_phys(va);
+            if pa & (pg - 1) != 0 {
+                ring.free(alloc);
+                return Err(NVMeError::InvBuf);
+            }
+
+            unsafe {
+                (va as *mut u8).write_bytes(0, pg);
+                (list_va as *mut u64).add(i).write_volatile(pa as u64);
+            }
+        }
+
+        return Ok(ring);
+    }
+
+    fn at(&self, off: usize) -> usize {
+        return self.pages[off / self.pg] + off % self.pg;
+    }
+
+    fn free<A: Dma>(&self, alloc: &A) {
+        match &self.list {
+            None => unsafe { alloc.free(self.pages[0], self.bytes); },
+            Some(list) => {
+                for &va in &self.pages {
+                    unsafe { alloc.free(va, self.pg); }
+                }
+                list.free(alloc);
+            }
+        }
+    }
+}
+
+pub struct Sq<A: Dma> {
+    qid: u16,
+    ring: Ring,
+    size: usize,
+    tail: Mutex<u16>,
+    head: AtomicU16,
+    dead: AtomicBool,
+    cids: Mutex<CidTab>,
+    pending: AtomicU16,
+    alloc: Arc<A>
+}
+
+impl<A: Dma> Sq<A> {
+    pub fn new(qid: u16, size: usize, pg: usize, contig: bool, alloc: &Arc<A>) -> Result<Self> {
+        let ring = Ring::new(alloc.as_ref(), size * 64, pg, contig)?;
 
         return Ok(Self {
             qid,
-            addr,
-            phys,
+            ring,
             size,
-            tail: AtomicU16::new(0),
-            cid: AtomicU16::new(0),
+            tail: Mutex::new(0),
+            head: AtomicU16::new(0),
+            dead: AtomicBool::new(false),
+            cids: Mutex::new(CidTab {
+                slots: (0..size).map(|_| Slot::Free).collect(),
+                next: 0
+            }),
             pending: AtomicU16::new(0),
-            _alloc: PhantomData
+            alloc: alloc.clone()
         });
     }
 
+    pub fn qid(&self) -> u16 {
+        return self.qid;
+    }
+
     pub fn phys(&self) -> u64 {
-        return self.phys;
+        return self.ring.phys;
+    }
+
+    pub fn contig(&self) -> bool {
+        return self.ring.list.is_none();
     }
 
     pub fn size(&self) -> usize {
         return self.size;
     }
 
-    pub fn next_cid(&self) -> u16 {
-        return self.cid.fetch_add(1, Ordering::Relaxed);
+    pub fn next_cid(&self) -> Result<u16> {
+        return self.take_cid(Slot::Wait(None));
     }
 
-    pub fn submit(&self, sqe: &Sqe, mmio: usize, dstrd: u8) {
-        self.pending.fetch_add(1, Ordering::SeqCst);
+    fn take_cid(&self, state: Slot) -> Result<u16> {
+        let mut tab = self.cids.lock();
+        let n = tab.slots.len();
+        for i in 0..n {
+            let cid = (tab.next + i) % n;
+            if matches!(tab.slots[cid], Slot::Free) {
+                tab.slots[cid] = state;
+                tab.next = (cid + 1) % n;
+                return Ok(cid as u16);
+            }
+        }
+        return Err(NVMeError::FullQp);
+    }
 
-        let tail = self.tail.load(Ordering::Acquire);
-        let next = (tail + 1) % (self.size as u16);
+    pub fn free_cid(&self, cid: u16) {
+        if let Some(slot) = self.cids.lock().slots.get_mut(cid as usize) {
+            *slot = Slot::Free;
+        }
+    }
 
-        unsafe {
-            let ptr = (self.addr + tail as usize * 64) as *mut Sqe;
-            ptr.write_volatile(*sqe);
+    pub fn lose_cid(&self, cid: u16) {
+        if let Some(slot) = self.cids.lock().slots.get_mut(cid as usize) {
+            *slot = match slot {
+                Slot::Done(_) => Slot::Free,
+                _ => Slot::Lost
+            };
+        }
+    }
 
-            let db = mmio + reg::doorbell_sq(self.qid, dstrd);
-            (db as *mut u32).write_volatile(next as u32);
+    fn complete(&self, cqe: &Cqe) -> Option<Option<Waker>> {
+        let mut tab = self.cids.try_lock()?;
+        let Some(slot) = tab.slots.get_mut(cqe.cid as usize) else {
+            return Some(None);
+        };
+
+        match core::mem::replace(slot, Slot::Free) {
+            Slot::Wait(waker) => {
+                *slot = Slot::Done(*cqe);
+                return Some(waker);
+            }
+            Slot::Done(prev) => *slot = Slot::Done(prev),
+            Slot::Free | Slot::Lost => {}
+        }
+        return Some(None);
+    }
+
+    fn take_done(&self, cid: u16, waker: Option<&Waker>) -> Option<Cqe> {
+        let mut tab = self.cids.lock();
+        let slot = tab.slots.get_mut(cid as usize)?;
+        return match slot {
+            Slot::Done(cqe) => {
+                let cqe = *cqe;
+                *slot = Slot::Free;
+                Some(cqe)
+            }
+            Slot::Wait(w) => {
+                if let Some(waker) = waker {
+                    *w = Some(waker.clone());
+                }
+                None
+            }
+            _ => None
+        };
+    }
+
+    pub fn submit<M: Mmio>(&self, sqe: &Sqe, mmio: &M, dstrd: u8) -> Result<()> {
+        return self.submit_many(core::slice::from_ref(sqe), mmio, dstrd);
+    }
+
+    pub fn submit_many<M: Mmio>(&self, sqes: &[Sqe], mmio: &M, dstrd: u8) -> Result<()> {
+        return self.submit_iter(sqes.iter().copied(), mmio, dstrd);
+    }
+
+    pub fn submit_iter<M: Mmio>(&self, sqes: impl ExactSizeIterator<Item = Sqe>, mmio: &M, dstrd: u8) -> Result<()> {
+        let mut tail = self.tail.lock();
+        if self.is_dead() {
+            return Err(NVMeError::Reset);
+        }
+        if sqes.len() > self.space_at(*tail) {
+            return Err(NVMeError::FullQp);
+        }
+
+        self.pending.fetch_add(sqes.len() as u16, Ordering::SeqCst);
+        let mut next = *tail;
+
+        for sqe in sqes {
+            unsafe {
+                let ptr = self.ring.at(next as usize * 64) as *mut Sqe;
+                ptr.write_volatile(sqe);
+            }
+            next = ((next as usize + 1) % self.size) as u16;
         }
+        mmio.write32(reg::doorbell_sq(self.qid, dstrd), next as u32);
+
+        *tail = next;
+        return Ok(());
+    }
+
+    pub fn space(&self) -> usize {
+        return self.space_at(*self.tail.lock());
+    }
+
+    pub fn occupancy(&self) -> usize {
+        return self.size - 1 - self.space();
+    }
+
+    fn space_at(&self, tail: u16) -> usize {
+        let head = self.head.load(Ordering::Acquire) as usize;
+        let used = (tail as usize + self.size - head) % self.size;
+        return self.size - 1 - used;
+    }
+
+    fn set_head(&self, sqhd: u16) {
+        if (sqhd as usize) < self.size {
+            self.head.store(sqhd, Ordering::Release);
+        }
+    }
+
+    pub fn kill(&self) {
+        let _tail = self.tail.lock();
+        self.dead.store(true, Ordering::Release);
+    }
 
-        self.tail.store(next, Ordering::Release);
+    pub fn is_dead(&self) -> bool {
+        return self.dead.load(Ordering::Acquire);
     }
 
     pub fn is_idle(&self) -> bool {
@@ -97,63 +344,184 @@ impl<A: Dma> Sq<A> {
     }
 }
 
+impl<A: Dma> Drop for Sq<A> {
+    fn drop(&mut self) {
+        self.ring.free(self.alloc.as_ref());
+    }
+}
+
 pub struct Cq<A: Dma> {
     qid: u16,
-    addr: usize,
-    phys: u64,
+    ring: Ring,
     size: usize,
     head: AtomicU16,
     phase: AtomicU8,
-    _alloc: PhantomData<A>
+    iv: Option<u16>,
+    reaper: Mutex<()>,
+    missed: AtomicBool,
+    sqs: Mutex<BTreeMap<u16, Weak<Sq<A>>>>,
+    alloc: Arc<A>
 }
 
 impl<A: Dma> Cq<A> {
-    pub fn new(qid: u16, size: usize, alloc: &A) -> Result<Self> {
-        let bytes = size * 16;
-        let addr = unsafe { alloc.alloc(bytes) };
-        if addr == 0 {
-            return Err(NVMeError::OoRam);
-        }
-
-        unsafe {
-            (addr as *mut u8).write_bytes(0, bytes);
-        }
-
-        let phys = alloc.virt_to_phys(addr) as u64;
+    pub fn new(qid: u16, size: usize, pg: usize, contig: bool, alloc: &Arc<A>) -> Result<Self> {
+        let ring = Ring::new(alloc.as_ref(), size * 16, pg, contig)?;
 
         return Ok(Self {
             qid,
-            addr,
-            phys,
+            ring,
             size,
             head: AtomicU16::new(0),
             phase: AtomicU8::new(1),
-            _alloc: PhantomData
+            iv: None,
+            reaper: Mutex::new(()),
+            missed: AtomicBool::new(false),
+            sqs: Mutex::new(BTreeMap::new()),
+            alloc: alloc.clone()
         });
     }
 
+    pub fn with_vector(mut self, iv: Option<u16>) -> Self {
+        self.iv = iv;
+        return self;
+    }
+
+    pub fn qid(&self) -> u16 {
+        return self.qid;
+    }
+
+    pub fn vector(&self) -> Option<u16> {
+        return self.iv;
+    }
+
+    pub fn attach(&self, sq: &Arc<Sq<A>>) {
+        self.sqs.lock().insert(sq.qid, Arc::downgrade(sq));
+    }
+
+    fn detach(&self, sq: &Sq<A>) {
+        let mut sqs = self.sqs.lock();
+        if sqs.get(&sq.qid).is_some_and(|w| core::ptr::eq(w.as_ptr(), sq)) {
+            sqs.remove(&sq.qid);
+        }
+    }
+
+    pub fn sq_cnt(&self) -> usize {
+        return self.sqs.lock().values().filter(|sq| sq.strong_count() > 0).count();
+    }
+
     pub fn phys(&self) -> u64 {
-        return self.phys;
+        return self.ring.phys;
+    }
+
+    pub fn contig(&self) -> bool {
+        return self.ring.list.is_none();
     }
 
     pub fn size(&self) -> usize {
         return self.size;
     }
 
-    pub fn poll(&self, cid: u16, mmio: usize, dstrd: u8) -> Result<Cqe> {
-        let phase = self.phase.load(Ordering::Acquire);
+    pub fn poll<M: Mmio>(
+        &self,
+        cid: u16,
+        sq: &Sq<A>,
+        mmio: &M,
+        dstrd: u8,
+        dl: &Deadline
+    ) -> Result<Cqe> {
+        return self.poll_raw(cid, sq, mmio, dstrd, dl)?.into_result();
+    }
+
+    pub fn poll_raw<M: Mmio>(
+        &self,
+        cid: u16,
+        sq: &Sq<A>,
+        mmio: &M,
+        dstrd: u8,
+        dl: &Deadline
+    ) -> Result<Cqe> {
+        let mut spins = 0u32;
+        let cqe = loop {
+            if self.iv.is_none() || self.missed.load(Ordering::Acquire) {
+                self.reap(mmio, dstrd);
+            }
+            if let Some(cqe) = sq.take_done(cid, None) {
+                break cqe;
+            }
+            if sq.is_dead() {
+                return Err(NVMeError::Reset);
+            }
+
+            spins = spins.wrapping_add(1);
+            if spins.is_multiple_of(1024) {
+                let csts = CstsReg::from_raw(mmio.read32(reg::CSTS));
+                if csts.is_fatal() || !csts.is_ready() {
+                    return Err(NVMeError::Reset);
+                }
+                self.reap(mmio, dstrd);
+            }
+            if dl.expired() {
+                return Err(NVMeError::Timeout);
+            }
+            if self.iv.is_some() {
+                spin_loop();
+            }
+        };
 
-        let mut head;
-        let mut cqe;
+        return Ok(cqe);
+    }
 
+    pub fn reap<M: Mmio>(&self, mmio: &M, dstrd: u8) -> usize {
+        let mut n = 0;
         loop {
-            head = self.head.load(Ordering::Acquire);
-            let ptr = (self.addr + head as usize * 16) as *const Cqe;
-            cqe = unsafe { ptr.read_volatile() };
-            if cqe.phase() == (phase != 0) && cqe.cid == cid {
-                break;
+            let mut wakers = Vec::new();
+            let res = self.drain(mmio, dstrd, &mut wakers);
+            for waker in wakers {
+                waker.wake();
+            }
+
+            match res {
+                Some(k) => n += k,
+                None => {
+                    self.missed.store(true, Ordering::Release);
+                    return n;
+                }
+            }
+            if !self.missed.swap(false, Ordering::AcqRel) {
+                return n;
             }
         }
+    }
+
+    fn drain<M: Mmio>(&self, mmio: &M, dstrd: u8, wakers: &mut Vec<Waker>) -> Option<usize> {
+        let _reaper = self.reaper.try_lock()?;
+        let sqs = self.sqs.try_lock()?;
+
+        let mut n = 0;
+        while let Some((head, phase, cqe)) = self.peek() {
+            if let Some(sq) = sqs.get(&cqe.sqid).and_then(Weak::upgrade) {
+                wakers.extend(sq.complete(&cqe)?);
+                sq.set_head(cqe.sqhd);
+            }
+            self.advance(head, phase, mmio, dstrd);
+            n += 1;
+        }
+        return Some(n);
+    }
+
+    fn peek(&self) -> Option<(u16, u8, Cqe)> {
+        let phase = self.phase.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        let ptr = self.ring.at(head as usize * 16) as *const Cqe;
+        let cqe = unsafe { ptr.read_volatile() };
+
+        if cqe.phase() != (phase != 0) {
+            return None;
+        }
+        return Some((head, phase, cqe));
+    }
+
+    fn advance<M: Mmio>(&self, head: u16, phase: u8, mmio: &M, dstrd: u8) {
         let next = (head + 1) % (self.size as u16);
 
         if next == 0 {
@@ -162,36 +530,73 @@ impl<A: Dma> Cq<A> {
 
         self.head.store(next, Ordering::Release);
 
-        let db = mmio + reg::doorbell_cq(self.qid, dstrd);
-        unsafe { (db as *mut u32).write_volatile(next as u32); }
-
-        if !cqe.ok() {
-            return Err(NVMeError::CmdFail(cqe.status()));
-        }
+        mmio.write32(reg::doorbell_cq(self.qid, dstrd), next as u32);
+    }
+}
 
-        return Ok(cqe);
+impl<A: Dma> Drop for Cq<A> {
+    fn drop(&mut self) {
+        self.ring.free(self.alloc.as_ref());
     }
 }
 
 pub struct Queue<A: Dma> {
     qid: u16,
-    sq: Sq<A>,
-    cq: Cq<A>
+    prio: QPrio,
+    sq: Arc<Sq<A>>,
+    cq: Arc<Cq<A>>
 }
 
 impl<A: Dma> Queue<A> {
-    pub fn new(qid: u16, size: usize, alloc: &A) -> Result<Self> {
+    pub fn new(qid: u16, size: usize, pg: usize, contig: bool, alloc: &Arc<A>) -> Result<Self> {
+        let cq = Arc::new(Cq::new(qid, size, pg, contig, alloc)?);
+        return Self::with_cq(qid, size, pg, contig, &cq, alloc);
+    }
+
+    pub fn with_cq(
+        qid: u16,
+        size: usize,
+        pg: usize,
+        contig: bool,
+        cq: &Arc<Cq<A>>,
+        alloc: &Arc<A>
+    ) -> Result<Self> {
+        let sq = Arc::new(Sq::new(qid, size, pg, contig, alloc)?);
+        cq.attach(&sq);
+
         return Ok(Self {
             qid,
-            sq: Sq::new(qid, size, alloc)?,
-            cq: Cq::new(qid, size, alloc)?
+            prio: QPrio::Urgent,
+            sq,
+            cq: cq.clone()
         });
     }
 
+    pub fn with_prio(mut self, prio: QPrio) -> Self {
+        self.prio = prio;
+        return self;
+    }
+
     pub fn qid(&self) -> u16 {
         return self.qid;
     }
 
+    pub fn cqid(&self) -> u16 {
+        return self.cq.qid();
+    }
+
+    pub fn prio(&self) -> QPrio {
+        return self.prio;
+    }
+
+    pub fn vector(&self) -> Option<u16> {
+        return self.cq.vector();
+    }
+
+    pub fn cq(&self) -> &Arc<Cq<A>> {
+        return &self.cq;
+    }
+
     pub fn sq_phys(&self) -> u64 {
         return self.sq.phys();
     }
@@ -200,22 +605,267 @@ impl<A: Dma> Queue<A> {
         return self.cq.phys();
     }
 
+    pub fn sq_contig(&self) -> bool {
+        return self.sq.contig();
+    }
+
     pub fn size(&self) -> usize {
         return self.sq.size() + self.cq.size();
     }
 
-    pub fn submit(&self, cmd: &Cmd, mmio: usize, dstrd: u8) -> Result<Cqe> {
-        let cid = self.sq.next_cid();
+    pub fn depth(&self) -> usize {
+        return self.sq.size();
+    }
+
+    pub fn kill(&self) {
+        self.sq.kill();
+    }
+
+    pub fn submit<M: Mmio>(
+        &self,
+        cmd: &Cmd,
+        mmio: &M,
+        dstrd: u8,
+        clock: &dyn Clock,
+        to_us: u64
+    ) -> Result<Cqe> {
+        let cid = self.issue(cmd, mmio, dstrd)?;
+        return self.wait(cid, mmio, dstrd, clock, to_us);
+    }
+
+    pub fn submit_raw<M: Mmio>(
+        &self,
+        cmd: &Cmd,
+        mmio: &M,
+        dstrd: u8,
+        clock: &dyn Clock,
+        to_us: u64
+    ) -> Result<Cqe> {
+        let cid = self.issue(cmd, mmio, dstrd)?;
+        return self.wait_raw(cid, mmio, dstrd, clock, to_us);
+    }
+
+    pub fn issue<M: Mmio>(&self, cmd: &Cmd, mmio: &M, dstrd: u8) -> Result<u16> {
+        let cid = self.sq.next_cid()?;
+        return self.push(cid, cmd, mmio, dstrd);
+    }
+
+    pub fn issue_wait<M: Mmio>(
+        &self,
+        cmd: &Cmd,
+        mmio: &M,
+        dstrd: u8,
+        clock: &dyn Clock,
+        to_us: u64
+    ) -> Result<u16> {
+        let dl = Deadline::new(clock, to_us);
+        loop {
+            match self.issue(cmd, mmio, dstrd) {
+                Err(NVMeError::FullQp) => {}
+                res => return res
+            }
+
+            self.reap(mmio, dstrd);
+            if dl.expired() {
+                return Err(NVMeError::Timeout);
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn submit_async<'a, M: Mmio>(
+        &'a self,
+        cmd: &Cmd,
+        mmio: &'a M,
+        dstrd: u8
+    ) -> Result<Completion<'a, A, M>> {
+        let cid = self.sq.take_cid(Slot::Wait(None))?;
+        self.push(cid, cmd, mmio, dstrd)?;
+
+        return Ok(Completion {
+            queue: self,
+            mmio,
+            dstrd,
+            cid,
+            done: false
+        });
+    }
+
+    pub fn reap<M: Mmio>(&self, mmio: &M, dstrd: u8) -> usize {
+        return self.cq.reap(mmio, dstrd);
+    }
+
+    pub fn issue_batch<M: Mmio>(&self, cmds: &[Cmd], mmio: &M, dstrd: u8) -> Result<Vec<u16>> {
+        let mut cids = Vec::with_capacity(cmds.len());
+        self.issue_batch_into(cmds, &mut cids, mmio, dstrd)?;
+        return Ok(cids);
+    }
+
+    pub fn issue_batch_into<M: Mmio>(&self, cmds: &[Cmd], cids: &mut Vec<u16>, mmio: &M, dstrd: u8) -> Result<()> {
+        cids.clear();
+        for _ in cmds {
+            match self.sq.next_cid() {
+                Ok(cid) => cids.push(cid),
+                Err(e) => {
+                    cids.drain(..).for_each(|cid| self.sq.free_cid(cid));
+                    return Err(e);
+                }
+            }
+        }
+
+        let sqes = cmds.iter().zip(cids.iter()).map(|(cmd, &cid)| cmd.to_sqe(cid));
+        if let Err(e) = self.sq.submit_iter(sqes, mmio, dstrd) {
+            cids.drain(..).for_each(|cid| self.sq.free_cid(cid));
+            return Err(e);
+        }
+        return Ok(());
+    }
+
+    pub fn space(&self) -> usize {
+        return self.sq.space();
+    }
+
+    pub fn occupancy(&self) -> usize {
+        return self.sq.occupancy();
+    }
+
+    pub fn inflight(&self) -> usize {
+        return self.sq.pending.load(Ordering::SeqCst) as usize;
+    }
+
+    fn push<M: Mmio>(&self, cid: u16, cmd: &Cmd, mmio: &M, dstrd: u8) -> Result<u16> {
         let sqe = cmd.to_sqe(cid);
-        self.sq.submit(&sqe, mmio, dstrd);
-        let result = self.cq.poll(cid, mmio, dstrd);
+        let res = self.sq.submit(&sqe, mmio, dstrd);
+        if res.is_err() {
+            self.sq.free_cid(cid);
+        }
 
-        self.sq.pending.fetch_sub(1, Ordering::SeqCst);
+        if self.cq.missed.load(Ordering::Acquire) {
+            self.cq.reap(mmio, dstrd);
+        }
+        return res.map(|_| cid);
+    }
+
+    pub fn wait<M: Mmio>(
+        &self,
+        cid: u16,
+        mmio: &M,
+        dstrd: u8,
+        clock: &dyn Clock,
+        to_us: u64
+    ) -> Result<Cqe> {
+        return self.wait_raw(cid, mmio, dstrd, clock, to_us)?.into_result();
+    }
+
+    pub fn wait_raw<M: Mmio>(
+        &self,
+        cid: u16,
+        mmio: &M,
+        dstrd: u8,
+        clock: &dyn Clock,
+        to_us: u64
+    ) -> Result<Cqe> {
+        let dl = Deadline::new(clock, to_us);
+        let res = self.poll_cid(cid, mmio, dstrd, &dl);
+        return self.finish(cid, res);
+    }
+
+    pub fn poll_cid<M: Mmio>(&self, cid: u16, mmio: &M, dstrd: u8, dl: &Deadline) -> Result<Cqe> {
+        return self.cq.poll_raw(cid, &self.sq, mmio, dstrd, dl);
+    }
+
+    pub fn try_take<M: Mmio>(&self, cid: u16, mmio: &M, dstrd: u8) -> Option<Cqe> {
+        self.cq.reap(mmio, dstrd);
+        return self.sq.take_done(cid, None);
+    }
 
-        return result;
+    pub fn finish(&self, cid: u16, res: Result<Cqe>) -> Result<Cqe> {
+        if res.is_err() {
+            self.sq.lose_cid(cid);
+        }
+        self.sq.pending.fetch_sub(1, Ordering::SeqCst);
+        return res;
     }
 
     pub fn is_idle(&self) -> bool {
         return self.sq.is_idle();
     }
 }
+
+impl<A: Dma> Drop for Queue<A> {
+    fn drop(&mut self) {
+        self.cq.detach(&self.sq);
+    }
+}
+
+pub struct Completion<'a, A: Dma, M: Mmio> {
+    queue: &'a Queue<A>,
+    mmio: &'a M,
+    dstrd: u8,
+    cid: u16,
+    done: bool
+}
+
+impl<A: Dma, M: Mmio> Completion<'_, A, M> {
+    pub fn cid(&self) -> u16 {
+        return self.cid;
+    }
+
+    pub fn try_get(&mut self) -> Option<Result<Cqe>> {
+        return self.check(None);
+    }
+
+    pub fn wait(mut self, clock: &dyn Clock, to_us: u64) -> Result<Cqe> {
+        let dl = Deadline::new(clock, to_us);
+        let res = self.queue.cq.poll(self.cid, &self.queue.sq, self.mmio, self.dstrd, &dl);
+
+        if !matches!(res, Err(NVMeError::Timeout) | Err(NVMeError::Reset)) {
+            self.done = true;
+            self.queue.sq.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        return res;
+    }
+
+    fn check(&mut self, waker: Option<&Waker>) -> Option<Result<Cqe>> {
+        if self.done {
+            return None;
+        }
+
+        self.queue.reap(self.mmio, self.dstrd);
+        let cqe = match self.queue.sq.take_done(self.cid, waker) {
+            Some(cqe) => cqe,
+            None if self.queue.sq.is_dead() => return Some(Err(NVMeError::Reset)),
+            None => return None
+        };
+
+        self.done = true;
+        self.queue.sq.pending.fetch_sub(1, Ordering::SeqCst);
+        return Some(cqe.into_result());
+    }
+}
+
+impl<A: Dma, M: Mmio> Future for Completion<'_, A, M> {
+    type Output = Result<Cqe>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Cqe>> {
+        let this = self.get_mut();
+        return match this.check(Some(cx.waker())) {
+            Some(res) => Poll::Ready(res),
+            None => {
+                if this.queue.vector().is_none() {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        };
+    }
+}
+
+impl<A: Dma, M: Mmio> Drop for Completion<'_, A, M> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.queue.sq.lose_cid(self.cid);
+            self.queue.sq.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -1,9 +1,20 @@
 use crate::{NVMeError, Result};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(feature = "std")]
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+#[cfg(feature = "std")]
+use std::alloc::{alloc_zeroed, dealloc, Layout};
 
 pub trait Dma: Send + Sync {
     unsafe fn alloc(&self, size: usize) -> usize;
     unsafe fn free(&self, addr: usize, size: usize);
     fn virt_to_phys(&self, va: usize) -> usize;
+
+    fn dma_ok(&self, _va: usize, _len: usize) -> bool {
+        return true;
+    }
 }
 
 pub struct PrpList {
@@ -20,15 +31,16 @@ impl PrpList {
 pub fn build_prp<A: Dma>(
     alloc: &A,
     buf: usize,
-    sz: usize
+    sz: usize,
+    pg: usize
 ) -> Result<(u64, u64, Option<PrpList>)> {
     if buf & 0x3 != 0 {
         return Err(NVMeError::InvBuf);
     }
 
     let prp1 = alloc.virt_to_phys(buf) as u64;
-    let off = buf & 0xFFF;
-    let pages = (off + sz + 4095) / 4096;
+    let off = buf & (pg - 1);
+    let pages = (off + sz).div_ceil(pg);
 
     if pages == 1 {
         return Ok((prp1, 0, None));
@@ -38,13 +50,15 @@ pub fn build_prp<A: Dma>(
         return Err(NVMeError::InvBuf);
     }
 
-    let prp2_pa = alloc.virt_to_phys(buf + 4096);
+    let prp2_pa = alloc.virt_to_phys(buf + pg);
 
     if pages == 2 {
         return Ok((prp1, prp2_pa as u64, None));
     }
 
-    let list_sz = (pages - 1) * 8;
+    let ents = pages - 1;
+    let per_pg = pg / 8;
+    let list_sz = (ents + ents.div_ceil(per_pg - 1)) * 8;
     let list_aligned = (list_sz + 4095) & !4095;
     let list_va = unsafe { alloc.alloc(list_aligned) };
 
@@ -52,15 +66,398 @@ pub fn build_prp<A: Dma>(
         return Err(NVMeError::OoRam);
     }
 
-    let list_ptr = list_va as *mut u64;
-    for i in 0..(pages - 1) {
-        let page_pa = alloc.virt_to_phys(buf + (i + 1) * 4096);
-        unsafe {
-            *list_ptr.add(i) = page_pa as u64;
+    let mut slot = list_va;
+    for i in 0..ents {
+        if ents - i > 1 && (alloc.virt_to_phys(slot) + 8).is_multiple_of(pg) {
+            unsafe { (slot as *mut u64).write(alloc.virt_to_phys(slot + 8) as u64); }
+            slot += 8;
         }
+
+        let page_pa = alloc.virt_to_phys(buf + (i + 1) * pg);
+        unsafe { (slot as *mut u64).write(page_pa as u64); }
+        slot += 8;
     }
 
     let list_pa = alloc.virt_to_phys(list_va) as u64;
 
     return Ok((prp1, list_pa, Some(PrpList { addr: list_va, sz: list_aligned })));
-}
\ No newline at end of file
+}
+
+pub const SGL_DATA: u8 = 0x0;
+pub const SGL_BIT_BUCKET: u8 = 0x1;
+pub const SGL_SEGMENT: u8 = 0x2;
+pub const SGL_LAST_SEG: u8 = 0x3;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SglDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub rsvd: [u8; 3],
+    pub id: u8
+}
+
+impl SglDesc {
+    pub fn new(kind: u8, addr: u64, len: u32) -> Self {
+        return Self { addr, len, rsvd: [0; 3], id: kind << 4 };
+    }
+
+    pub fn data(addr: u64, len: u32) -> Self {
+        return Self::new(SGL_DATA, addr, len);
+    }
+
+    pub fn bit_bucket(len: u32) -> Self {
+        return Self::new(SGL_BIT_BUCKET, 0, len);
+    }
+
+    pub fn segment(addr: u64, len: u32) -> Self {
+        return Self::new(SGL_SEGMENT, addr, len);
+    }
+
+    pub fn last_seg(addr: u64, len: u32) -> Self {
+        return Self::new(SGL_LAST_SEG, addr, len);
+    }
+
+    pub fn kind(&self) -> u8 {
+        return self.id >> 4;
+    }
+
+    pub fn dptr(&self) -> (u64, u64) {
+        return (self.addr, self.len as u64 | (self.id as u64) << 56);
+    }
+}
+
+pub fn sgl_descs<A: Dma>(alloc: &A, buf: usize, sz: usize, pg: usize) -> Vec<SglDesc> {
+    let mut descs: Vec<SglDesc> = Vec::new();
+    let mut off = 0;
+    while off < sz {
+        let n = (pg - ((buf + off) & (pg - 1))).min(sz - off);
+        let pa = alloc.virt_to_phys(buf + off) as u64;
+
+        match descs.last_mut() {
+            Some(d) if d.addr + d.len as u64 == pa && d.len as u64 + (n as u64) <= u32::MAX as u64 => {
+                d.len += n as u32;
+            }
+            _ => descs.push(SglDesc::data(pa, n as u32))
+        }
+        off += n;
+    }
+    return descs;
+}
+
+pub fn build_sgl<A: Dma>(alloc: &A, descs: &[SglDesc]) -> Result<(u64, u64, Option<PrpList>)> {
+    if descs.is_empty() {
+        return Err(NVMeError::InvBuf);
+    }
+
+    if descs.len() == 1 {
+        let (dp1, dp2) = descs[0].dptr();
+        return Ok((dp1, dp2, None));
+    }
+
+    let list_sz = size_of_val(descs);
+    let list_aligned = (list_sz + 4095) & !4095;
+    let list_va = unsafe { alloc.alloc(list_aligned) };
+
+    if list_va == 0 {
+        return Err(NVMeError::OoRam);
+    }
+
+    let list_ptr = list_va as *mut SglDesc;
+    for (i, desc) in descs.iter().enumerate() {
+        unsafe {
+            list_ptr.add(i).write_volatile(*desc);
+        }
+    }
+
+    let list_pa = alloc.virt_to_phys(list_va) as u64;
+    let (dp1, dp2) = SglDesc::last_seg(list_pa, list_sz as u32).dptr();
+
+    return Ok((dp1, dp2, Some(PrpList { addr: list_va, sz: list_aligned })));
+}
+
+pub const POOL_BLK: usize = 4096;
+
+pub struct DmaPool<A: Dma> {
+    alloc: Arc<A>,
+    free: Mutex<Vec<usize>>,
+    cap: AtomicUsize,
+    misses: AtomicU64
+}
+
+impl<A: Dma> DmaPool<A> {
+    pub fn new(alloc: &Arc<A>) -> Self {
+        return Self {
+            alloc: alloc.clone(),
+            free: Mutex::new(Vec::new()),
+            cap: AtomicUsize::new(0),
+            misses: AtomicU64::new(0)
+        };
+    }
+
+    pub fn reserve(&self, n: usize) -> Result<()> {
+        let cap = self.cap.fetch_add(n, Ordering::Relaxed) + n;
+        let mut free = self.free.lock();
+        free.reserve(n);
+
+        while free.len() < cap {
+            let addr = unsafe { self.alloc.alloc(POOL_BLK) };
+            if addr == 0 {
+                drop(free);
+                self.unreserve(n);
+                return Err(NVMeError::OoRam);
+            }
+            free.push(addr);
+        }
+        return Ok(());
+    }
+
+    pub fn unreserve(&self, n: usize) {
+        let prev = self.cap
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cap| Some(cap.saturating_sub(n)))
+            .unwrap_or(0);
+        let cap = prev.saturating_sub(n);
+
+        let mut free = self.free.lock();
+        while free.len() > cap {
+            if let Some(addr) = free.pop() {
+                unsafe { self.alloc.free(addr, POOL_BLK); }
+            }
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        return self.cap.load(Ordering::Relaxed);
+    }
+
+    pub fn pooled(&self) -> usize {
+        return self.free.lock().len();
+    }
+
+    pub fn misses(&self) -> u64 {
+        return self.misses.load(Ordering::Relaxed);
+    }
+}
+
+impl<A: Dma> Dma for DmaPool<A> {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        if size > POOL_BLK {
+            return unsafe { self.alloc.alloc(size) };
+        }
+
+        if let Some(addr) = self.free.lock().pop() {
+            return addr;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        return unsafe { self.alloc.alloc(POOL_BLK) };
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        if size > POOL_BLK {
+            return unsafe { self.alloc.free(addr, size) };
+        }
+
+        let mut free = self.free.lock();
+        if free.len() < self.cap.load(Ordering::Relaxed) {
+            free.push(addr);
+            return;
+        }
+        drop(free);
+        unsafe { self.alloc.free(addr, POOL_BLK); }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        return self.alloc.virt_to_phys(va);
+    }
+
+    fn dma_ok(&self, va: usize, len: usize) -> bool {
+        return self.alloc.dma_ok(va, len);
+    }
+}
+
+impl<A: Dma> Drop for DmaPool<A> {
+    fn drop(&mut self) {
+        for addr in self.free.get_mut().drain(..) {
+            unsafe { self.alloc.free(addr, POOL_BLK); }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BounceStats {
+    pub hits: u64,
+    pub bytes: u64,
+    pub allocs: u64,
+    pub pooled: usize
+}
+
+pub struct BouncePool<A: Dma> {
+    alloc: Arc<A>,
+    free: Mutex<Vec<(usize, usize)>>,
+    keep: usize,
+    hits: AtomicU64,
+    bytes: AtomicU64,
+    allocs: AtomicU64
+}
+
+impl<A: Dma> BouncePool<A> {
+    pub fn new(alloc: &Arc<A>, keep: usize) -> Self {
+        return Self {
+            alloc: alloc.clone(),
+            free: Mutex::new(Vec::with_capacity(keep)),
+            keep,
+            hits: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            allocs: AtomicU64::new(0)
+        };
+    }
+
+    pub fn take(&self, sz: usize) -> Result<(usize, usize)> {
+        let mut free = self.free.lock();
+        let buf = match free.iter().position(|&(_, bsz)| bsz >= sz) {
+            Some(i) => free.swap_remove(i),
+            None => {
+                drop(free);
+                let bsz = (sz.max(1) + 4095) & !4095;
+                let addr = unsafe { self.alloc.alloc(bsz) };
+                if addr == 0 {
+                    return Err(NVMeError::OoRam);
+                }
+                self.allocs.fetch_add(1, Ordering::Relaxed);
+                (addr, bsz)
+            }
+        };
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(sz as u64, Ordering::Relaxed);
+        return Ok(buf);
+    }
+
+    pub fn give(&self, addr: usize, sz: usize) {
+        let mut free = self.free.lock();
+        if free.len() < self.keep {
+            free.push((addr, sz));
+            return;
+        }
+
+        let (addr, sz) = match free.iter().position(|&(_, bsz)| bsz < sz) {
+            Some(i) => core::mem::replace(&mut free[i], (addr, sz)),
+            None => (addr, sz)
+        };
+        drop(free);
+        unsafe { self.alloc.free(addr, sz); }
+    }
+
+    pub fn stats(&self) -> BounceStats {
+        return BounceStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            allocs: self.allocs.load(Ordering::Relaxed),
+            pooled: self.free.lock().len()
+        };
+    }
+}
+
+impl<A: Dma> Drop for BouncePool<A> {
+    fn drop(&mut self) {
+        for (addr, sz) in self.free.get_mut().drain(..) {
+            unsafe { self.alloc.free(addr, sz); }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+struct HeapInner {
+    off: usize,
+    live: Mutex<BTreeMap<usize, usize>>,
+    bad: Mutex<Vec<(usize, usize)>>
+}
+
+#[cfg(feature = "std")]
+impl HeapInner {
+    fn layout(size: usize) -> Layout {
+        let size = (size.max(1) + 4095) & !4095;
+        return Layout::from_size_align(size, size.next_power_of_two().min(65536)).unwrap();
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for HeapInner {
+    fn drop(&mut self) {
+        let live = core::mem::take(&mut *self.live.lock());
+        let bad = self.bad.lock().len();
+
+        for (&addr, &size) in &live {
+            unsafe { dealloc(addr as *mut u8, Self::layout(size)); }
+        }
+
+        if (!live.is_empty() || bad != 0) && !std::thread::panicking() {
+            let bytes = live.values().sum::<usize>();
+            panic!(
+                "HeapDma: {} leaked allocation(s) ({} bytes), {} double or mismatched free(s)",
+                live.len(), bytes, bad
+            );
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct HeapDma {
+    inner: Arc<HeapInner>
+}
+
+#[cfg(feature = "std")]
+impl HeapDma {
+    pub fn new() -> Self {
+        return Self::with_off(0);
+    }
+
+    pub fn with_off(off: usize) -> Self {
+        return Self {
+            inner: Arc::new(HeapInner {
+                off,
+                live: Mutex::new(BTreeMap::new()),
+                bad: Mutex::new(Vec::new())
+            })
+        };
+    }
+
+    pub fn outstanding(&self) -> usize {
+        return self.inner.live.lock().len();
+    }
+
+    pub fn outstanding_bytes(&self) -> usize {
+        return self.inner.live.lock().values().sum();
+    }
+
+    pub fn bad_frees(&self) -> Vec<(usize, usize)> {
+        return self.inner.bad.lock().clone();
+    }
+}
+
+#[cfg(feature = "std")]
+impl Dma for HeapDma {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        let addr = unsafe { alloc_zeroed(HeapInner::layout(size)) } as usize;
+        if addr != 0 {
+            self.inner.live.lock().insert(addr, size);
+        }
+        return addr;
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        let mut live = self.inner.live.lock();
+        match live.get(&addr) {
+            Some(&sz) if sz == size => {
+                live.remove(&addr);
+                unsafe { dealloc(addr as *mut u8, HeapInner::layout(sz)); }
+            }
+            _ => self.inner.bad.lock().push((addr, size))
+        }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        return va.wrapping_add(self.inner.off);
+    }
+}
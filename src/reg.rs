@@ -25,8 +25,11 @@ pub const PMRMSCU: usize = 0xE18;
 
 pub const CC_EN: u32 = 1 << 0;
 pub const CC_CSS_NVM: u32 = 0 << 4;
+pub const CC_CSS_IOCS: u32 = 6 << 4;
+pub const CC_CSS_NOIO: u32 = 7 << 4;
 pub const CC_MPS_SHIFT: u32 = 7;
 pub const CC_AMS_RR: u32 = 0 << 11;
+pub const CC_AMS_WRR: u32 = 1 << 11;
 pub const CC_SHN_NONE: u32 = 0 << 14;
 pub const CC_SHN_NORMAL: u32 = 1 << 14;
 pub const CC_SHN_ABRUPT: u32 = 2 << 14;
@@ -52,6 +55,8 @@ pub const CAP_DSTRD_SHIFT: u64 = 32;
 pub const CAP_DSTRD_MASK: u64 = 0xF;
 pub const CAP_NSSRS: u64 = 1 << 36;
 pub const CAP_CSS_NVM: u64 = 1 << 37;
+pub const CAP_CSS_IOCS: u64 = 1 << 43;
+pub const CAP_CSS_NOIO: u64 = 1 << 44;
 pub const CAP_BPS: u64 = 1 << 45;
 pub const CAP_MPSMIN_SHIFT: u64 = 48;
 pub const CAP_MPSMIN_MASK: u64 = 0xF;
@@ -60,6 +65,40 @@ pub const CAP_MPSMAX_MASK: u64 = 0xF;
 pub const CAP_PMRS: u64 = 1 << 56;
 pub const CAP_CMBS: u64 = 1 << 57;
 
+pub trait Mmio: Send + Sync {
+    fn read32(&self, off: usize) -> u32;
+    fn write32(&self, off: usize, val: u32);
+
+    fn read64(&self, off: usize) -> u64 {
+        let lo = self.read32(off) as u64;
+        let hi = self.read32(off + 4) as u64;
+        return (hi << 32) | lo;
+    }
+
+    fn write64(&self, off: usize, val: u64) {
+        self.write32(off, val as u32);
+        self.write32(off + 4, (val >> 32) as u32);
+    }
+}
+
+impl Mmio for usize {
+    fn read32(&self, off: usize) -> u32 {
+        return unsafe { ((self + off) as *const u32).read_volatile() };
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        unsafe { ((self + off) as *mut u32).write_volatile(val); }
+    }
+
+    fn read64(&self, off: usize) -> u64 {
+        return unsafe { ((self + off) as *const u64).read_volatile() };
+    }
+
+    fn write64(&self, off: usize, val: u64) {
+        unsafe { ((self + off) as *mut u64).write_volatile(val); }
+    }
+}
+
 pub fn doorbell_sq(qid: u16, dstrd: u8) -> usize {
     0x1000 + (2 * qid as usize) * (4 << dstrd)
 }
@@ -107,6 +146,14 @@ impl CapReg {
         return (self.value & CAP_CSS_NVM) != 0;
     }
 
+    pub fn css_iocs(&self) -> bool {
+        return (self.value & CAP_CSS_IOCS) != 0;
+    }
+
+    pub fn css_noio(&self) -> bool {
+        return (self.value & CAP_CSS_NOIO) != 0;
+    }
+
     pub fn bps(&self) -> bool {
         return (self.value & CAP_BPS) != 0;
     }
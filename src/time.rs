@@ -0,0 +1,62 @@
+use core::{hint::spin_loop, sync::atomic::{AtomicU64, Ordering}};
+
+pub trait Clock: Send + Sync {
+    fn now_us(&self) -> u64;
+}
+
+/// Counts calls to `now_us` rather than measuring time: each poll advances
+/// the clock by one "microsecond", so real timeouts scale with CPU and MMIO
+/// speed. Only use it where no timer is available.
+pub struct PollClock {
+    ticks: AtomicU64
+}
+
+impl PollClock {
+    pub fn new() -> Self {
+        return Self { ticks: AtomicU64::new(0) };
+    }
+}
+
+impl Clock for PollClock {
+    fn now_us(&self) -> u64 {
+        spin_loop();
+        return self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct StdClock {
+    start: std::time::Instant
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> Self {
+        return Self { start: std::time::Instant::now() };
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now_us(&self) -> u64 {
+        return self.start.elapsed().as_micros() as u64;
+    }
+}
+
+pub struct Deadline<'a> {
+    clock: &'a dyn Clock,
+    end: u64
+}
+
+impl<'a> Deadline<'a> {
+    pub fn new(clock: &'a dyn Clock, us: u64) -> Self {
+        return Self {
+            clock,
+            end: clock.now_us().saturating_add(us)
+        };
+    }
+
+    pub fn expired(&self) -> bool {
+        return self.clock.now_us() >= self.end;
+    }
+}
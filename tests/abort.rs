@@ -0,0 +1,156 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, dev, emu_cfg};
+use nvme_oxide::{Ctrl, Emu, HeapDma, Mmio, NVMeError, Ns};
+use std::{
+    sync::{atomic::{AtomicBool, AtomicU16, Ordering}, Arc, Barrier},
+    thread,
+    time::Duration
+};
+
+#[test]
+fn timed_out_command_is_aborted() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    let ns = dev.ns(1).unwrap();
+    ctrl.set_io_timeout(20_000);
+
+    let qid = ctrl.io_qids()[0];
+    emu.set_stall(qid, true);
+    let mut v = vec![0u8; 2 * 4096];
+    let buf = aligned(&mut v, 4096);
+    assert!(matches!(ns.read(0, buf).unwrap_err().err, NVMeError::Aborted));
+    assert_eq!(emu.resets(), 0);
+
+    emu.set_stall(qid, false);
+    ns.read(0, buf).unwrap();
+}
+
+#[test]
+fn unconfirmed_abort_resets_controller() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    let ns = dev.ns(1).unwrap();
+    ctrl.set_io_timeout(20_000);
+
+    let qid = ctrl.io_qids()[0];
+    emu.set_stall(qid, true);
+    emu.set_abort_fail(true);
+    let mut v = vec![0u8; 2 * 4096];
+    let buf = aligned(&mut v, 4096);
+    assert!(matches!(ns.read(0, buf).unwrap_err().err, NVMeError::Timeout));
+    assert_eq!(emu.resets(), 1);
+
+    emu.set_stall(qid, false);
+    emu.set_abort_fail(false);
+    buf.fill(0x77);
+    ns.write(0, buf).unwrap();
+    assert!(emu.ns_data(1).unwrap()[..4096].iter().all(|&b| b == 0x77));
+}
+
+#[derive(Clone)]
+struct Late {
+    emu: Emu,
+    sqid: Arc<AtomicU16>
+}
+
+impl Mmio for Late {
+    fn read32(&self, off: usize) -> u32 {
+        return self.emu.read32(off);
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        let sqid = self.sqid.swap(0, Ordering::SeqCst);
+        if off == 0x1000 && sqid != 0 {
+            self.emu.set_stall(sqid, false);
+        } else if sqid != 0 {
+            self.sqid.store(sqid, Ordering::SeqCst);
+        }
+        self.emu.write32(off, val);
+    }
+}
+
+#[test]
+fn completion_racing_abort_does_not_reset() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let mmio = Late { emu: emu.clone(), sqid: Arc::new(AtomicU16::new(0)) };
+    let ctrl = Arc::new(Ctrl::new(mmio.clone(), dma.clone(), &cfg()).unwrap());
+    let ns = Ns::new(ctrl.clone(), 1).unwrap();
+    ctrl.set_io_timeout(20_000);
+
+    let qid = ctrl.io_qids()[0];
+    let mut v = vec![0u8; 2 * 4096];
+    let buf = aligned(&mut v, 4096);
+    buf.fill(0x5A);
+    ns.write(0, buf).unwrap();
+
+    emu.set_stall(qid, true);
+    mmio.sqid.store(qid, Ordering::SeqCst);
+    buf.fill(0);
+    ns.read(0, buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0x5A));
+    assert_eq!(emu.resets(), 0);
+    assert!(ctrl.io_queue(qid).unwrap().is_idle());
+}
+
+#[derive(Clone)]
+struct SlowAdmin(Emu, Arc<AtomicBool>);
+
+impl Mmio for SlowAdmin {
+    fn read32(&self, off: usize) -> u32 {
+        return self.0.read32(off);
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        if off == 0x1000 && self.1.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        self.0.write32(off, val);
+    }
+}
+
+#[test]
+fn concurrent_timeouts_reset_once() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let mut c = cfg();
+    c.set_io_queues(1);
+    let slow = Arc::new(AtomicBool::new(false));
+    let ctrl = Arc::new(Ctrl::new(SlowAdmin(emu.clone(), slow.clone()), dma.clone(), &c).unwrap());
+    let ns = Arc::new(Ns::new(ctrl.clone(), 1).unwrap());
+    ctrl.set_io_timeout(50_000);
+
+    let qid = ctrl.io_qids()[0];
+    emu.set_stall(qid, true);
+    emu.set_abort_fail(true);
+    slow.store(true, Ordering::SeqCst);
+    let start = Arc::new(Barrier::new(4));
+    let threads = (0..4u64).map(|t| {
+        let (ns, start) = (ns.clone(), start.clone());
+        return thread::spawn(move || {
+            let mut v = vec![0u8; 2 * 4096];
+            let buf = aligned(&mut v, 512);
+            start.wait();
+            let err = ns.read(t, buf).unwrap_err().err;
+            assert!(matches!(err, NVMeError::Timeout | NVMeError::Reset), "{err}");
+        });
+    }).collect::<Vec<_>>();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(emu.resets(), 1);
+
+    slow.store(false, Ordering::SeqCst);
+    emu.set_stall(qid, false);
+    emu.set_abort_fail(false);
+    let mut v = vec![0u8; 2 * 4096];
+    ns.read(0, aligned(&mut v, 512)).unwrap();
+}
@@ -0,0 +1,93 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, block_on, dev, emu_cfg};
+use nvme_oxide::{Cmd, Emu, HeapDma, NVMeError, StdClock};
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    task::{Context, Poll, Wake, Waker}
+};
+
+struct Count(AtomicUsize);
+
+impl Wake for Count {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn submit_and_wait() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ns = dev.ns(1).unwrap();
+        let mut wv = vec![0u8; 64 * 512 + 4096];
+        let w = aligned(&mut wv, 64 * 512);
+        for (i, b) in w.iter_mut().enumerate() {
+            *b = (i / 512) as u8;
+        }
+        ns.write(0, w).unwrap();
+
+        let ctrl = dev.ctrl();
+        let q = ctrl.io_queue(ctrl.io_qids()[0]).unwrap();
+        let mut rv = vec![0u8; 33 * 4096];
+        let r = aligned(&mut rv, 32 * 4096);
+        let mut pending = Vec::new();
+        for i in 0..32 {
+            let cmd = Cmd::read(1, i as u64, 1, r[i * 4096..].as_ptr() as u64, 0);
+            pending.push(q.submit_async(&cmd, ctrl.mmio(), ctrl.dstrd()).unwrap());
+        }
+
+        let clock = StdClock::new();
+        while let Some(c) = pending.pop() {
+            if pending.len() % 2 == 0 {
+                c.wait(&clock, 1_000_000).unwrap();
+            } else {
+                block_on(c).unwrap();
+            }
+        }
+        assert!(q.is_idle());
+        for i in 0..32 {
+            assert!(r[i * 4096..i * 4096 + 512].iter().all(|&b| b == i as u8));
+        }
+
+        let cmd = Cmd::read(1, 5000, 1, r.as_ptr() as u64, 0);
+        let c = q.submit_async(&cmd, ctrl.mmio(), ctrl.dstrd()).unwrap();
+        assert!(matches!(c.wait(&clock, 1_000_000), Err(NVMeError::CmdFail(_))));
+        drop(q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap());
+        ns.flush().unwrap();
+        assert!(q.is_idle());
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn polled_completion_wakes_itself() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    let qid = ctrl.io_qids()[0];
+    let q = ctrl.io_queue(qid).unwrap();
+
+    emu.set_stall(qid, true);
+    let wakes = Arc::new(Count(AtomicUsize::new(0)));
+    let waker = Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap());
+
+    assert!(fut.as_mut().poll(&mut cx).is_pending());
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+
+    emu.set_stall(qid, false);
+    assert!(matches!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(_))));
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev};
+use nvme_oxide::{Emu, EmuCfg, HeapDma};
+
+fn doorbells(emu: &Emu) -> u64 {
+    return (1..16).map(|q| emu.sq_doorbells(q)).sum();
+}
+
+#[test]
+fn one_doorbell_per_batch() {
+    for (mqes, want) in [(1023u16, 1u64), (15, 3)] {
+        let mut ecfg = EmuCfg::new();
+        ecfg.mqes = mqes;
+        ecfg.add_ns(512, 4096);
+        let emu = Emu::new(&ecfg);
+        let dma = HeapDma::new();
+        {
+            let dev = dev(&emu, &dma);
+            let ns = dev.ns(1).unwrap();
+
+            let mut wv = vec![0u8; 41 * 4096];
+            let w = aligned(&mut wv, 40 * 4096);
+            for (i, b) in w.iter_mut().enumerate() {
+                *b = (i / 4096 * 3 + 1) as u8;
+            }
+            let reqs = w.chunks(4096)
+                .enumerate()
+                .map(|(i, c)| ((i * 50) as u64, &c[..512]))
+                .collect::<Vec<(u64, &[u8])>>();
+            let dbs = doorbells(&emu);
+            ns.write_many(&reqs).unwrap();
+            assert_eq!(doorbells(&emu) - dbs, want);
+
+            let mut rv = vec![0u8; 41 * 4096];
+            let r = aligned(&mut rv, 40 * 4096);
+            let mut reqs = r.chunks_mut(4096)
+                .enumerate()
+                .map(|(i, c)| ((i * 50) as u64, &mut c[..512]))
+                .collect::<Vec<(u64, &mut [u8])>>();
+            let dbs = doorbells(&emu);
+            ns.read_many(&mut reqs).unwrap();
+            assert_eq!(doorbells(&emu) - dbs, want);
+            for i in 0..40 {
+                assert!(r[i * 4096..i * 4096 + 512].iter().all(|&b| b == (i * 3 + 1) as u8));
+            }
+
+            let mut reqs = r.chunks_mut(4096)
+                .take(3)
+                .enumerate()
+                .map(|(i, c)| ((i * 3000) as u64, &mut c[..512]))
+                .collect::<Vec<(u64, &mut [u8])>>();
+            assert_eq!(ns.read_many(&mut reqs).unwrap_err().lba, 6000);
+        }
+        assert_eq!(dma.outstanding(), 0);
+    }
+}
@@ -0,0 +1,125 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, aligned_to, cfg, dev, emu_cfg, pattern};
+use nvme_oxide::{BouncePool, Dma, Emu, HeapDma, NVMeDev, NVMeError};
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct Picky(HeapDma);
+
+impl Dma for Picky {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        return unsafe { self.0.alloc(size) };
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        unsafe { self.0.free(addr, size) }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        return self.0.virt_to_phys(va);
+    }
+
+    fn dma_ok(&self, va: usize, _len: usize) -> bool {
+        return va.is_multiple_of(8192) || va % 8192 >= 4096;
+    }
+}
+
+#[test]
+fn unaligned_buffer_without_bounce_fails() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let heap = HeapDma::new();
+    {
+        let dev = dev(&emu, &heap);
+        let ns = dev.ns(1).unwrap();
+        let mut v = vec![0u8; 512 * 20 + 8192];
+        let buf = &aligned(&mut v, 512 * 20 + 2)[2..];
+        assert!(matches!(ns.write(0, buf).unwrap_err().err, NVMeError::InvBuf));
+        assert!(dev.ctrl().bounce_stats().is_none());
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
+
+#[test]
+fn bounce_buffers_are_pooled() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let heap = HeapDma::new();
+    {
+        let mut c = cfg();
+        c.set_bounce(Some(2)).set_io_queues(2);
+        let dev = NVMeDev::new(emu.clone(), Picky(heap.clone()), &c).unwrap();
+        let ns = dev.ns(1).unwrap();
+        let ctrl = dev.ctrl();
+
+        let len = 512 * 300;
+        let mut v = vec![0u8; len + 8192];
+        let buf = &mut aligned(&mut v, len + 2)[2..];
+        pattern(buf, 11);
+        ns.write(10, buf).unwrap();
+
+        let mut v2 = vec![0u8; len + 8192];
+        let out = &mut aligned(&mut v2, len + 6)[6..];
+        ns.read(10, out).unwrap();
+        assert!(buf == out);
+        ns.compare(10, buf).unwrap();
+
+        let mut small = vec![0u8; 1024];
+        let mut reqs = [(10u64, &mut small[..])];
+        ns.read_many(&mut reqs).unwrap();
+        assert!(small[..] == buf[..1024]);
+
+        let st = ctrl.bounce_stats().unwrap();
+        assert!(st.hits >= 6, "{st:?}");
+        assert!(st.allocs <= 4, "{st:?}");
+        assert!(st.pooled <= 2);
+
+        let before = st.hits;
+        let mut v3 = vec![0u8; 4096 * 3];
+        let page = aligned_to(&mut v3, 8192, 4096);
+        ns.read(10, page).unwrap();
+        assert_eq!(ctrl.bounce_stats().unwrap().hits, before);
+        ns.read(10, &mut page[512..1024]).unwrap();
+        assert_eq!(ctrl.bounce_stats().unwrap().hits, before + 1);
+        assert!(page[512..1024] == buf[..512]);
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
+
+#[derive(Clone)]
+struct Tight(HeapDma);
+
+impl Dma for Tight {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        if size > 8192 {
+            return 0;
+        }
+        return unsafe { self.0.alloc(size) };
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        unsafe { self.0.free(addr, size) }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        return self.0.virt_to_phys(va);
+    }
+}
+
+#[test]
+fn failed_take_is_not_counted() {
+    let heap = HeapDma::new();
+    {
+        let pool = BouncePool::new(&Arc::new(Tight(heap.clone())), 2);
+        assert!(matches!(pool.take(3 * 4096), Err(NVMeError::OoRam)));
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.bytes, stats.allocs), (0, 0, 0));
+
+        let (addr, sz) = pool.take(100).unwrap();
+        pool.give(addr, sz);
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.bytes, stats.allocs, stats.pooled), (1, 100, 1, 1));
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
@@ -0,0 +1,50 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg};
+use nvme_oxide::{CtrlId, Emu, HeapDma, NVMeError, SanitizeKind};
+
+#[test]
+fn identify_is_cached_and_complete() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dev = dev(&emu, &HeapDma::new());
+    let ctrl = dev.ctrl();
+    let id = ctrl.identify().unwrap();
+    assert_eq!(id.serial(), "EMU00000001");
+    assert_eq!({ id.nn }, 1);
+    assert!(id.has_vwc());
+    assert!(ctrl.supports_dsm() && ctrl.supports_write_zeroes() && !ctrl.supports_ns_mgmt());
+    assert!(ctrl.supports_sanitize(SanitizeKind::Overwrite));
+    assert_eq!({ ctrl.ctrl_id().oncs }, { id.oncs });
+    assert_eq!(std::mem::size_of::<CtrlId>(), 4096);
+
+    let ns = dev.ns(1).unwrap();
+    ns.trim(0, 4).unwrap();
+    ns.write_zeroes(0, 4).unwrap();
+}
+
+#[test]
+fn missing_capabilities_are_unsupported() {
+    let mut ecfg = emu_cfg(512, 64);
+    ecfg.oncs = 0;
+    ecfg.sanicap = 0x1;
+    ecfg.oacs = 1 << 3;
+    let emu = Emu::new(&ecfg);
+    let dev = dev(&emu, &HeapDma::new());
+    let ctrl = dev.ctrl();
+    assert!(ctrl.supports_ns_mgmt());
+
+    let ns = dev.ns(1).unwrap();
+    assert!(matches!(ns.trim(0, 4), Err(NVMeError::Unsupported)));
+    assert!(matches!(ns.write_zeroes(0, 4), Err(NVMeError::Unsupported)));
+    assert!(matches!(ns.verify(3, 4).unwrap_err().err, NVMeError::Unsupported));
+    let mut v = vec![0u8; 8192];
+    let err = ns.compare(5, aligned(&mut v, 512)).unwrap_err();
+    assert!(err.lba == 5 && matches!(err.err, NVMeError::Unsupported));
+
+    assert!(matches!(ctrl.block_erase(), Err(NVMeError::Unsupported)));
+    assert!(matches!(ctrl.overwrite(1, false), Err(NVMeError::Unsupported)));
+    ctrl.crypto_erase().unwrap();
+    assert_eq!(NVMeError::Unsupported.to_string(), "command not supported by controller");
+}
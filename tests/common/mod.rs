@@ -0,0 +1,55 @@
+#![allow(dead_code)]
+
+use nvme_oxide::{CtrlConfig, Emu, EmuCfg, HeapDma, NVMeDev, StdClock};
+use std::{
+    future::Future,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker}
+};
+
+pub fn cfg() -> CtrlConfig {
+    return CtrlConfig::new(StdClock::new());
+}
+
+pub fn emu_cfg(blk_sz: usize, blk_cnt: u64) -> EmuCfg {
+    let mut cfg = EmuCfg::new();
+    cfg.add_ns(blk_sz, blk_cnt);
+    return cfg;
+}
+
+pub fn dev(emu: &Emu, dma: &HeapDma) -> Arc<NVMeDev<HeapDma, Emu>> {
+    return NVMeDev::new(emu.clone(), dma.clone(), &cfg()).unwrap();
+}
+
+pub fn aligned(v: &mut [u8], len: usize) -> &mut [u8] {
+    return aligned_to(v, 4096, len);
+}
+
+pub fn aligned_to(v: &mut [u8], align: usize, len: usize) -> &mut [u8] {
+    let off = (align - v.as_ptr() as usize % align) % align;
+    return &mut v[off..off + len];
+}
+
+pub fn pattern(buf: &mut [u8], seed: u8) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (i % 251) as u8 ^ seed;
+    }
+}
+
+struct Noop;
+
+impl Wake for Noop {
+    fn wake(self: Arc<Self>) {}
+}
+
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Noop));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+    }
+}
@@ -0,0 +1,92 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned_to, cfg, pattern};
+use nvme_oxide::{CmdSet, Ctrl, Emu, EmuCfg, HeapDma, Mmio, NVMeDev, NVMeError, QPrio};
+
+#[test]
+fn invalid_configs_are_rejected() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mqes = 1023;
+    ecfg.add_ns(512, 8192);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    for bad in [
+        { let mut c = cfg(); c.set_admin_depth(1); c },
+        { let mut c = cfg(); c.set_admin_depth(2048); c },
+        { let mut c = cfg(); c.set_io_depth(4096); c },
+        { let mut c = cfg(); c.set_page_size(6000); c },
+        { let mut c = cfg(); c.set_page_size(1 << 20); c },
+        { let mut c = cfg(); c.set_cmd_set(CmdSet::AdminOnly); c },
+        { let mut c = cfg(); c.set_cmd_set(CmdSet::AllIo); c }
+    ] {
+        assert!(matches!(Ctrl::new(emu.clone(), dma.clone(), &bad).err(), Some(NVMeError::InvCfg)));
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn options_are_applied_and_survive_reset() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mqes = 1023;
+    ecfg.add_ns(512, 8192);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let mut c = cfg();
+        c.set_admin_depth(32).set_io_queues(3).set_io_depth(64).set_page_size(16384).set_async_events(true);
+        let dev = NVMeDev::new(emu.clone(), dma.clone(), &c).unwrap();
+        let ctrl = dev.ctrl();
+        assert_eq!(emu.read32(0x24), (31 << 16) | 31);
+        assert_eq!(ctrl.ioq_cnt(), 3);
+        assert_eq!(ctrl.io_queue(ctrl.io_qids()[0]).unwrap().depth(), 64);
+        assert_eq!(ctrl.page_size(), 16384);
+        assert_eq!((emu.read32(0x14) >> 7) & 0xF, 2);
+        assert_ne!(ctrl.get_feat(0x0B).unwrap(), 0);
+
+        let ns = dev.ns(1).unwrap();
+        let len = 512 * 200;
+        let mut v = vec![0u8; len + 16384];
+        let buf = aligned_to(&mut v, 16384, len);
+        pattern(buf, 7);
+        ns.write(5, buf).unwrap();
+
+        let mut v2 = vec![0u8; len + 16384];
+        let out = aligned_to(&mut v2, 16384, len);
+        ns.read(5, out).unwrap();
+        assert!(buf == out);
+
+        ctrl.reset().unwrap();
+        assert_eq!((emu.read32(0x14) >> 7) & 0xF, 2);
+        out.fill(0);
+        ns.read(5, out).unwrap();
+        assert!(buf == out);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn depths_fit_in_sixteen_bits() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mqes = 0xFFFF;
+    ecfg.add_ns(512, 64);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    for bad in [
+        { let mut c = cfg(); c.set_io_depth(65536); c },
+        { let mut c = cfg(); c.set_admin_depth(65536); c },
+        { let mut c = cfg(); c.set_io_queues(0); c }
+    ] {
+        assert!(matches!(Ctrl::new(emu.clone(), dma.clone(), &bad).err(), Some(NVMeError::InvCfg)));
+    }
+    {
+        let dev = NVMeDev::new(emu.clone(), dma.clone(), &cfg()).unwrap();
+        let ctrl = dev.ctrl();
+        assert!(matches!(ctrl.new_qpair(65536, QPrio::Medium).err(), Some(NVMeError::InvCfg)));
+        assert!(matches!(ctrl.create_cq(65536, None), Err(NVMeError::InvCfg)));
+        assert!(matches!(ctrl.new_qpair(1, QPrio::Medium).err(), Some(NVMeError::InvCfg)));
+        dev.ns(1).unwrap().flush().unwrap();
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
@@ -0,0 +1,66 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg, pattern};
+use nvme_oxide::{Dma, Emu, EmuCfg, HeapDma};
+
+#[test]
+fn device_drop_frees_everything() {
+    let emu = Emu::new(&emu_cfg(512, 1024));
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        ctrl.set_ioq_cnt(4).unwrap();
+        let ns = dev.ns(1).unwrap();
+
+        let mut v = vec![0u8; 9 * 4096];
+        let buf = aligned(&mut v, 8 * 4096);
+        pattern(buf, 1);
+        ns.write(0, buf).unwrap();
+        ns.read(0, buf).unwrap();
+        ns.trim(0, 8).unwrap();
+        ctrl.set_ioq_cnt(1).unwrap();
+        assert!(dma.outstanding() > 0);
+    }
+    assert_eq!(dma.outstanding(), 0);
+    assert_eq!(dma.outstanding_bytes(), 0);
+    assert!(dma.bad_frees().is_empty());
+}
+
+#[test]
+fn phys_offset_is_honoured() {
+    let mut cfg = EmuCfg::new();
+    cfg.add_ns(512, 64);
+    cfg.phys_off = 0x1000_0000;
+    let emu = Emu::new(&cfg);
+    let dma = HeapDma::with_off(0x1000_0000);
+    let dev = dev(&emu, &dma);
+    let ns = dev.ns(1).unwrap();
+
+    let mut v = vec![0u8; 3 * 4096];
+    let buf = aligned(&mut v, 2 * 4096);
+    pattern(buf, 9);
+    ns.write(0, buf).unwrap();
+    assert_eq!(&emu.ns_data(1).unwrap()[..buf.len()], &buf[..]);
+}
+
+#[test]
+#[should_panic(expected = "leaked")]
+fn leak_panics_on_drop() {
+    let dma = HeapDma::new();
+    unsafe { dma.alloc(100); }
+}
+
+#[test]
+fn double_free_is_recorded() {
+    let dma = HeapDma::new();
+    let addr = unsafe { dma.alloc(100) };
+    unsafe {
+        dma.free(addr, 100);
+        dma.free(addr, 100);
+    }
+    assert_eq!(dma.bad_frees(), vec![(addr, 100)]);
+    std::mem::forget(dma);
+}
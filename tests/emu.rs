@@ -0,0 +1,66 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, pattern};
+use nvme_oxide::{Emu, EmuCfg, HeapDma};
+
+#[test]
+fn read_write_round_trip() {
+    let mut cfg = EmuCfg::new();
+    cfg.add_ns(512, 4096).add_ns(4096, 256);
+    let emu = Emu::new(&cfg);
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    assert_eq!(dev.ns_list().len(), 2);
+
+    let ns = dev.ns(1).unwrap();
+    assert_eq!((ns.blk_sz(), ns.blk_cnt()), (512, 4096));
+    assert_eq!(dev.ns(2).unwrap().blk_sz(), 4096);
+
+    let mut wv = vec![0u8; 4 * 4096];
+    let w = aligned(&mut wv, 3 * 4096);
+    pattern(w, 0x5A);
+    ns.write(8, w).unwrap();
+
+    let mut rv = vec![0u8; 4 * 4096];
+    let r = aligned(&mut rv, 3 * 4096);
+    ns.read(8, r).unwrap();
+    assert_eq!(r, w);
+    assert_eq!(&emu.ns_data(1).unwrap()[8 * 512..8 * 512 + w.len()], &w[..]);
+
+    ns.flush().unwrap();
+    ns.trim(8, 1).unwrap();
+    let data = emu.ns_data(1).unwrap();
+    assert!(data[8 * 512..9 * 512].iter().all(|&b| b == 0));
+    assert_eq!(data[9 * 512], w[512]);
+}
+
+#[test]
+fn admin_commands() {
+    let emu = Emu::new(&common::emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+
+    assert_eq!(ctrl.data().serial, "EMU00000001");
+    assert_eq!(ctrl.data().model, "nvme-oxide emulated controller");
+    assert_eq!(ctrl.version(), 0x0001_0400);
+    assert_eq!(ctrl.reg_nss().unwrap(), vec![1]);
+    assert_eq!(ctrl.set_ioq_cnt(4).unwrap(), 4);
+    assert_eq!(ctrl.ioq_cnt(), 4);
+    assert!(ctrl.error_log(4).unwrap().is_empty());
+    ctrl.smart_log().unwrap();
+}
+
+#[test]
+fn shutdown_and_resume() {
+    let emu = Emu::new(&common::emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+
+    ctrl.shutdown().unwrap();
+    ctrl.resume().unwrap();
+    ctrl.get_feat(0x07).unwrap();
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::cfg;
+use nvme_oxide::{Cmd, Ctrl, Emu, EmuCfg, HeapDma, NVMeError, Ns, StdClock};
+use std::{sync::Arc, thread, time::{Duration, Instant}};
+
+#[test]
+fn full_queue_rejects_or_blocks() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mqes = 15;
+    ecfg.add_ns(512, 4096);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let ctrl = Arc::new(Ctrl::new(emu.clone(), dma.clone(), &cfg()).unwrap());
+        ctrl.set_io_timeout(3_000_000);
+        let ns = Ns::new(ctrl.clone(), 1).unwrap();
+        let qid = ctrl.io_qids()[0];
+        let q = ctrl.io_queue(qid).unwrap();
+        assert_eq!(q.space(), 15);
+
+        emu.set_stall(qid, true);
+        let pending = (0..15)
+            .map(|_| q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(q.occupancy(), 15);
+        assert_eq!(q.inflight(), 15);
+        assert!(matches!(q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()), Err(NVMeError::FullQp)));
+        assert!(matches!(ns.flush(), Err(NVMeError::FullQp)));
+
+        ctrl.set_block_on_full(true);
+        let unstall = {
+            let emu = emu.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                emu.set_stall(qid, false);
+            })
+        };
+        let start = Instant::now();
+        ns.flush().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        unstall.join().unwrap();
+
+        let clock = StdClock::new();
+        for c in pending {
+            c.wait(&clock, 1_000_000).unwrap();
+        }
+        assert_eq!(q.occupancy(), 0);
+        assert!(q.is_idle());
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
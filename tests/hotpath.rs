@@ -0,0 +1,92 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg, pattern};
+use nvme_oxide::{Emu, HeapDma, Mmio, NVMeDev, QPrio};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell
+};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCS: Cell<usize> = const { Cell::new(0) };
+    static DEVICE: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !DEVICE.with(|d| d.get()) {
+            ALLOCS.with(|n| n.set(n.get() + 1));
+        }
+        return unsafe { System.alloc(layout) };
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocs() -> usize {
+    return ALLOCS.with(|n| n.get());
+}
+
+#[derive(Clone)]
+struct Device(Emu);
+
+impl Mmio for Device {
+    fn read32(&self, off: usize) -> u32 {
+        DEVICE.with(|d| d.set(true));
+        let val = self.0.read32(off);
+        DEVICE.with(|d| d.set(false));
+        return val;
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        DEVICE.with(|d| d.set(true));
+        self.0.write32(off, val);
+        DEVICE.with(|d| d.set(false));
+    }
+}
+
+#[test]
+fn pair_io_does_not_allocate() {
+    let mut ecfg = emu_cfg(512, 8192);
+    ecfg.mdts = 3;
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let mut c = cfg();
+        c.set_io_queues(1);
+        let dev = NVMeDev::new(Device(emu.clone()), dma.clone(), &c).unwrap();
+        let ns = dev.ns(1).unwrap();
+        let mut qp = dev.ctrl().new_qpair(8, QPrio::Medium).unwrap();
+
+        let len = 32768 * 10;
+        let mut v = vec![0u8; len + 4096];
+        let buf = aligned(&mut v, len);
+        pattern(buf, 5);
+        ns.write_qp(&mut qp, 0, buf).unwrap();
+        ns.read_qp(&mut qp, 0, &mut buf[..512]).unwrap();
+
+        let before = allocs();
+        for i in 0..20u64 {
+            ns.write_qp(&mut qp, i, &buf[..4096]).unwrap();
+            ns.read_qp(&mut qp, i, &mut buf[..512]).unwrap();
+            ns.read_qp(&mut qp, 0, buf).unwrap();
+            ns.compare_qp(&mut qp, 0, buf).unwrap();
+            ns.verify_qp(&mut qp, 0, 640).unwrap();
+            ns.write_zeroes_qp(&mut qp, 700, 8).unwrap();
+            ns.trim_qp(&mut qp, 800, 8).unwrap();
+            ns.flush_qp(&mut qp).unwrap();
+        }
+        assert_eq!(allocs(), before);
+        assert_eq!(qp.pool().misses(), 0);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
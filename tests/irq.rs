@@ -0,0 +1,191 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg};
+use nvme_oxide::{Cmd, Ctrl, Emu, HeapDma, Mmio, NVMeError, Ns, QPrio, StatusCode};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, OnceLock, Weak},
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant}
+};
+
+#[test]
+fn vectors_and_masking() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    {
+        let ctrl = Ctrl::new(emu.clone(), dma.clone(), &cfg()).unwrap();
+        let res = ctrl.new_ioq_irq(64, 8, QPrio::Medium);
+        assert!(matches!(res, Err(NVMeError::CmdFail(s)) if s.code() == StatusCode::InvalidVector));
+
+        ctrl.new_ioq_irq(64, 3, QPrio::Medium).unwrap();
+        let qid = *ctrl.io_qids().iter().max().unwrap();
+        let q = ctrl.io_queue(qid).unwrap();
+        assert_eq!(q.vector(), Some(3));
+
+        let before = emu.irqs(3);
+        let mut pending = (0..4)
+            .map(|_| q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(emu.irqs(3), before + 4);
+        assert_eq!(ctrl.on_interrupt(2), 0);
+        assert_eq!(ctrl.on_interrupt(3), 4);
+        for c in pending.iter_mut() {
+            assert!(c.try_get().unwrap().is_ok());
+        }
+        drop(pending);
+
+        ctrl.int_mask(1 << 3);
+        let c = q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap();
+        assert_eq!(emu.irqs(3), before + 4);
+        ctrl.int_unmask(1 << 3);
+        assert_eq!(emu.irqs(3), before + 5);
+        assert_eq!(ctrl.on_interrupt(3), 1);
+        drop(c);
+
+        ctrl.reset().unwrap();
+        assert_eq!(ctrl.io_queue(qid).unwrap().vector(), Some(3));
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn sync_io_completes_from_interrupts() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    let ctrl = Arc::new(Ctrl::new(emu.clone(), dma.clone(), &cfg()).unwrap());
+    let polled = ctrl.io_qids();
+    ctrl.new_ioq_irq(64, 1, QPrio::Medium).unwrap();
+    for qid in polled {
+        ctrl.rm_ioq(qid).unwrap();
+    }
+    let ns = Ns::new(ctrl.clone(), 1).unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let irq = {
+        let (ctrl, stop) = (ctrl.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut n = 0;
+            while !stop.load(Ordering::Relaxed) {
+                n += ctrl.on_interrupt(1);
+            }
+            return n;
+        })
+    };
+
+    let mut v = vec![0u8; 2 * 4096];
+    let buf = aligned(&mut v, 512);
+    for _ in 0..500 {
+        ns.read(0, buf).unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    assert!(irq.join().unwrap() > 0);
+}
+
+#[derive(Clone)]
+struct Reentrant {
+    emu: Emu,
+    ctrl: Arc<OnceLock<Weak<Ctrl<HeapDma, Reentrant>>>>,
+    irqs: Arc<AtomicUsize>
+}
+
+impl Mmio for Reentrant {
+    fn read32(&self, off: usize) -> u32 {
+        return self.emu.read32(off);
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        self.emu.write32(off, val);
+        if off >= 0x1000
+            && let Some(ctrl) = self.ctrl.get().and_then(Weak::upgrade)
+        {
+            for vector in 0..4 {
+                self.irqs.fetch_add(ctrl.on_interrupt(vector), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[test]
+fn interrupt_during_submit_does_not_deadlock() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    let mmio = Reentrant { emu, ctrl: Arc::new(OnceLock::new()), irqs: Arc::new(AtomicUsize::new(0)) };
+    let ctrl = Arc::new(Ctrl::new(mmio.clone(), dma.clone(), &cfg()).unwrap());
+    mmio.ctrl.set(Arc::downgrade(&ctrl)).unwrap();
+
+    ctrl.new_ioq_irq(64, 2, QPrio::Medium).unwrap();
+    let cqid = ctrl.create_cq(32, Some(3)).unwrap();
+    let sqid = ctrl.create_sq(cqid, 32, QPrio::Medium).unwrap();
+
+    let ns = Ns::new(ctrl.clone(), 1).unwrap();
+    let mut v = vec![0u8; 2 * 4096];
+    let buf = aligned(&mut v, 4096);
+    for lba in 0..64 {
+        ns.write(lba, buf).unwrap();
+        ns.read(lba, buf).unwrap();
+    }
+
+    ctrl.rm_ioq(sqid).unwrap();
+    ctrl.rm_cq(cqid).unwrap();
+    assert!(mmio.irqs.load(Ordering::Relaxed) > 0);
+}
+
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn interrupt_while_cqs_are_busy_is_not_lost() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    let ctrl = Arc::new(Ctrl::new(emu.clone(), dma.clone(), &cfg()).unwrap());
+    let cqid = ctrl.create_cq(16, Some(2)).unwrap();
+    let sqid = ctrl.create_sq(cqid, 16, QPrio::Medium).unwrap();
+    let queue = ctrl.io_queue(sqid).unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let busy = (0..3).map(|_| {
+        let (ctrl, stop) = (ctrl.clone(), stop.clone());
+        return thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                ctrl.on_interrupt(7);
+            }
+        });
+    }).collect::<Vec<_>>();
+
+    for _ in 0..2000 {
+        emu.set_stall(sqid, true);
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut c = queue.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap();
+        assert!(Pin::new(&mut c).poll(&mut cx).is_pending());
+        emu.set_stall(sqid, false);
+        ctrl.on_interrupt(2);
+
+        let start = Instant::now();
+        while !flag.0.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(1), "interrupt was lost");
+            thread::yield_now();
+        }
+        assert!(matches!(Pin::new(&mut c).poll(&mut cx), Poll::Ready(Ok(_))));
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for t in busy {
+        t.join().unwrap();
+    }
+    drop(queue);
+    ctrl.rm_ioq(sqid).unwrap();
+    ctrl.rm_cq(cqid).unwrap();
+}
@@ -0,0 +1,48 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg};
+use nvme_oxide::{Ctrl, Emu, HeapDma, Mmio, Ns};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+struct Trace {
+    emu: Emu,
+    writes: Arc<Mutex<Vec<(usize, u32)>>>
+}
+
+impl Mmio for Trace {
+    fn read32(&self, off: usize) -> u32 {
+        return self.emu.read32(off);
+    }
+
+    fn write32(&self, off: usize, val: u32) {
+        self.writes.lock().unwrap().push((off, val));
+        self.emu.write32(off, val);
+    }
+}
+
+#[test]
+fn ctrl_runs_over_custom_mmio() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let mmio = Trace { emu: emu.clone(), writes: Arc::new(Mutex::new(Vec::new())) };
+    let ctrl = Arc::new(Ctrl::new(mmio.clone(), HeapDma::new(), &cfg()).unwrap());
+
+    let writes = mmio.writes.lock().unwrap().clone();
+    let cc = writes.iter().rev().find(|(off, _)| *off == 0x14).unwrap().1;
+    assert_eq!(cc & 1, 1);
+    assert_eq!((cc >> 16) & 0xF, 6);
+    assert_eq!((cc >> 20) & 0xF, 4);
+    assert!(writes.iter().any(|(off, _)| *off == 0x1000));
+
+    let ns = Ns::new(ctrl.clone(), 1).unwrap();
+    let mut v = vec![0u8; 2 * 4096];
+    let buf = aligned(&mut v, 512);
+    buf.fill(0x3C);
+    ns.write(0, buf).unwrap();
+    assert!(emu.ns_data(1).unwrap()[..512].iter().all(|&b| b == 0x3C));
+
+    let sq1 = mmio.writes.lock().unwrap().iter().filter(|(off, _)| *off == 0x1008).count();
+    assert_eq!(sq1 as u64, emu.sq_doorbells(1));
+}
@@ -0,0 +1,126 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg};
+use nvme_oxide::{Dma, Emu, HeapDma, NVMeDev, NVMeError};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+struct Scattered {
+    heap: HeapDma,
+    big: bool,
+    live: Arc<Mutex<Vec<(usize, usize)>>>
+}
+
+impl Dma for Scattered {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        if size > 4096 && !self.big {
+            return 0;
+        }
+        let addr = unsafe { self.heap.alloc(size) };
+        if size > 4096 {
+            self.live.lock().unwrap().push((addr, size));
+        }
+        return addr;
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        self.live.lock().unwrap().retain(|&(a, _)| a != addr);
+        unsafe { self.heap.free(addr, size) }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        let gap = self.live.lock().unwrap().iter().any(|&(a, s)| va >= a + 4096 && va < a + s);
+        return if gap { va + (1 << 44) } else { va };
+    }
+}
+
+#[test]
+fn queues_span_scattered_pages() {
+    for cqr in [false, true] {
+        let mut ecfg = emu_cfg(512, 8192);
+        ecfg.cqr = cqr;
+        let emu = Emu::new(&ecfg);
+        let heap = HeapDma::new();
+        {
+            let mut c = cfg();
+            c.set_admin_depth(64).set_io_depth(1024).set_io_queues(2);
+            let dma = Scattered { heap: heap.clone(), big: cqr, live: Default::default() };
+            let res = NVMeDev::new(emu.clone(), dma, &c);
+            if cqr {
+                assert!(matches!(res.err(), Some(NVMeError::NonContig)));
+                continue;
+            }
+
+            let dev = res.unwrap();
+            let ctrl = dev.ctrl();
+            assert!(!ctrl.contig_queues());
+            let ns = dev.ns(1).unwrap();
+            let mut v = vec![0u8; 4096 * 2];
+            let buf = aligned(&mut v, 512);
+            for i in 0..3000u64 {
+                buf[0] = i as u8;
+                buf[511] = (i >> 8) as u8;
+                ns.write(i, buf).unwrap();
+            }
+            for i in 0..3000u64 {
+                ns.read(i, buf).unwrap();
+                assert_eq!((buf[0], buf[511]), (i as u8, (i >> 8) as u8));
+            }
+
+            ctrl.reset().unwrap();
+            ns.read(5, buf).unwrap();
+            assert_eq!((buf[0], buf[511]), (5, 0));
+        }
+        assert_eq!(heap.outstanding(), 0);
+    }
+}
+
+#[derive(Clone)]
+struct Capped(HeapDma);
+
+impl Dma for Capped {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        if size > 16384 {
+            return 0;
+        }
+        return unsafe { self.0.alloc(size) };
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        unsafe { self.0.free(addr, size) }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        return self.0.virt_to_phys(va);
+    }
+}
+
+#[test]
+fn prp_list_uses_controller_pages() {
+    let mut ecfg = emu_cfg(512, 8192);
+    ecfg.mpsmin = 2;
+    ecfg.cqr = false;
+    let emu = Emu::new(&ecfg);
+    let heap = HeapDma::new();
+    {
+        let mut c = cfg();
+        c.set_admin_depth(64).set_io_depth(1024).set_page_size(16384);
+        let dev = NVMeDev::new(emu.clone(), Capped(heap.clone()), &c).unwrap();
+        let ctrl = dev.ctrl();
+        assert!(!ctrl.contig_queues());
+        assert_eq!(ctrl.page_size(), 16384);
+
+        let ns = dev.ns(1).unwrap();
+        let mut v = vec![0u8; 4096 * 2];
+        let buf = aligned(&mut v, 512);
+        for i in 0..2000u64 {
+            buf[0] = i as u8;
+            ns.write(i, buf).unwrap();
+            ns.read(i, buf).unwrap();
+            assert_eq!(buf[0], i as u8);
+        }
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned_to, cfg, pattern};
+use nvme_oxide::{Ctrl, Emu, EmuCfg, HeapDma, NVMeDev, NVMeError};
+
+fn emu() -> Emu {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mpsmin = 2;
+    ecfg.mpsmax = 3;
+    ecfg.add_ns(4096, 4096);
+    return Emu::new(&ecfg);
+}
+
+#[test]
+fn unsupported_page_sizes_are_rejected() {
+    let emu = emu();
+    let dma = HeapDma::new();
+
+    let mut c = cfg();
+    c.set_page_size(4096);
+    assert!(matches!(Ctrl::new(emu.clone(), dma.clone(), &c).err(), Some(NVMeError::InvCfg)));
+
+    let mut c = cfg();
+    c.set_host_page_size(12288);
+    assert!(matches!(Ctrl::new(emu.clone(), dma.clone(), &c).err(), Some(NVMeError::InvCfg)));
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn host_page_size_picks_controller_page() {
+    let emu = emu();
+    let dma = HeapDma::new();
+    for (host, want) in [(4096, 16384), (16384, 16384), (65536, 32768)] {
+        let mut c = cfg();
+        c.set_host_page_size(host).set_io_queues(2);
+        let dev = NVMeDev::new(emu.clone(), dma.clone(), &c).unwrap();
+        let ctrl = dev.ctrl();
+        assert_eq!(ctrl.page_size(), want);
+        for qid in ctrl.io_qids() {
+            let q = ctrl.io_queue(qid).unwrap();
+            assert_eq!(q.sq_phys() as usize % want, 0);
+            assert_eq!(q.cq_phys() as usize % want, 0);
+        }
+
+        let ns = dev.ns(1).unwrap();
+        let len = 4096 * 100;
+        let mut v = vec![0u8; len + 65536];
+        let buf = aligned_to(&mut v, 65536, len);
+        pattern(buf, host as u8 ^ (host >> 12) as u8);
+        ns.write(7, buf).unwrap();
+
+        let mut v2 = vec![0u8; len + 65536];
+        let out = aligned_to(&mut v2, 65536, len);
+        ns.read(7, out).unwrap();
+        assert!(buf == out);
+
+        ctrl.reset().unwrap();
+        out.fill(0);
+        ns.read(7, out).unwrap();
+        assert!(buf == out);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
@@ -0,0 +1,138 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg};
+use nvme_oxide::{
+    Cmd, DataDir, Emu, HeapDma, NVMeDev, NVMeError, Passthru, QPrio, StatusCode, FUSE_FIRST, PSDT_SGL
+};
+
+fn id_cmd() -> Cmd {
+    let mut cmd = Cmd::new(0x06);
+    cmd.cdw10 = 1;
+    return cmd;
+}
+
+#[test]
+fn raw_commands_return_cqe() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let mut c = cfg();
+    c.set_bounce(Some(2));
+    let dev = NVMeDev::new(emu.clone(), HeapDma::new(), &c).unwrap();
+    let ctrl = dev.ctrl();
+
+    let mut id = vec![0u8; 4096];
+    let cqe = ctrl.admin_passthru(Passthru::new(id_cmd()).set_data(&mut id, DataDir::FromDev)).unwrap();
+    assert!(cqe.ok());
+    assert_eq!(&id[4..15], b"EMU00000001");
+
+    let mut w = (0..1024).map(|i| i as u8).collect::<Vec<u8>>();
+    let mut cmd = Cmd::new(0x01);
+    cmd.nsid = 1;
+    cmd.cdw10 = 4;
+    cmd.cdw12 = 1;
+    cmd.cdw13 = 0x7;
+    let cqe = ctrl.io_passthru(Passthru::new(cmd).set_data(&mut w, DataDir::ToDev)).unwrap();
+    assert!(cqe.ok());
+    assert_eq!(&emu.ns_data(1).unwrap()[2048..3072], &w[..]);
+
+    let mut r = vec![0u8; 1025];
+    let mut cmd = Cmd::new(0x02);
+    cmd.nsid = 1;
+    cmd.cdw10 = 4;
+    cmd.cdw12 = 1;
+    let cqe = ctrl.io_passthru(Passthru::new(cmd).set_data(&mut r[1..], DataDir::FromDev)).unwrap();
+    assert!(cqe.ok());
+    assert_eq!(&r[1..], &w[..]);
+
+    let mut cmd = Cmd::new(0x02);
+    cmd.nsid = 1;
+    cmd.cdw10 = 1000;
+    let cqe = ctrl.io_passthru(&mut Passthru::new(cmd)).unwrap();
+    assert_eq!(cqe.status().code(), StatusCode::LbaOutOfRange);
+    let cqe = ctrl.admin_passthru(&mut Passthru::new(Cmd::new(0xC5))).unwrap();
+    assert_eq!(cqe.status().code(), StatusCode::InvalidOpcode);
+
+    let sqe = Cmd::flush(1).with_fuse(FUSE_FIRST).with_psdt(PSDT_SGL).to_sqe(7);
+    let back = Cmd::from_sqe(&sqe);
+    assert!(back.fuse == FUSE_FIRST && back.psdt == PSDT_SGL && back.opc == 0 && back.nsid == 1);
+}
+
+#[test]
+fn fused_compare_and_write() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dev = NVMeDev::new(emu.clone(), HeapDma::new(), &cfg()).unwrap();
+    let ctrl = dev.ctrl();
+
+    let mut old = vec![0x55u8; 512];
+    let mut cmd = Cmd::new(0x01);
+    cmd.nsid = 1;
+    cmd.cdw10 = 4;
+    ctrl.io_passthru(Passthru::new(cmd).set_data(&mut old, DataDir::ToDev)).unwrap();
+
+    let mut bad = vec![0u8; 512];
+    let mut new = vec![0xAAu8; 512];
+    let mut cmp = Cmd::new(0x05);
+    cmp.nsid = 1;
+    cmp.cdw10 = 4;
+    let (a, b) = ctrl.io_fused(
+        Passthru::new(cmp).set_data(&mut bad, DataDir::ToDev),
+        Passthru::new(cmd).set_data(&mut new, DataDir::ToDev)
+    ).unwrap();
+    assert_eq!(a.status().code(), StatusCode::CompareFailure);
+    assert_eq!(b.status().code(), StatusCode::AbortFusedFail);
+    assert_eq!(&emu.ns_data(1).unwrap()[2048..2560], &old[..]);
+
+    let (a, b) = ctrl.io_fused(
+        Passthru::new(cmp).set_data(&mut old, DataDir::ToDev),
+        Passthru::new(cmd).set_data(&mut new, DataDir::ToDev)
+    ).unwrap();
+    assert!(a.ok() && b.ok());
+    assert_eq!(&emu.ns_data(1).unwrap()[2048..2560], &[0xAA; 512][..]);
+
+    let mut qp = ctrl.new_qpair(16, QPrio::Medium).unwrap();
+    let mut m = vec![0u8; 8];
+    let mut r = vec![0u8; 512];
+    let mut rd = Cmd::new(0x02);
+    rd.nsid = 1;
+    rd.cdw10 = 4;
+    let cqe = qp.passthru(Passthru::new(rd).set_data(&mut r, DataDir::FromDev).set_meta(&mut m)).unwrap();
+    assert!(cqe.ok() && r == [0xAA; 512]);
+}
+
+#[test]
+fn admin_passthru_uses_prps_only() {
+    let mut ecfg = emu_cfg(512, 64);
+    ecfg.sgls = 1 | (1 << 16);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let dev = NVMeDev::new(emu.clone(), dma.clone(), &cfg()).unwrap();
+        let ctrl = dev.ctrl();
+
+        let mut v = vec![0u8; 4096 * 3];
+        let id = aligned(&mut v, 4096);
+        let res = ctrl.admin_passthru(Passthru::new(id_cmd().with_psdt(PSDT_SGL)).set_data(id, DataDir::FromDev));
+        assert!(matches!(res, Err(NVMeError::InvCfg)));
+
+        let odd = &mut aligned(&mut v, 4096 + 2)[2..];
+        let res = ctrl.admin_passthru(Passthru::new(id_cmd()).set_data(odd, DataDir::FromDev));
+        assert!(matches!(res, Err(NVMeError::InvBuf)));
+
+        let mut cmd = Cmd::new(0x02);
+        cmd.nsid = 1;
+        let odd = &mut aligned(&mut v, 512 + 2)[2..];
+        assert!(ctrl.io_passthru(Passthru::new(cmd).set_data(odd, DataDir::FromDev)).unwrap().ok());
+    }
+    {
+        let mut c = cfg();
+        c.set_bounce(Some(1));
+        let dev = NVMeDev::new(emu.clone(), dma.clone(), &c).unwrap();
+        let mut v = vec![0u8; 4096 * 3];
+        let odd = &mut aligned(&mut v, 4096 + 2)[2..];
+        let cqe = dev.ctrl().admin_passthru(Passthru::new(id_cmd()).set_data(odd, DataDir::FromDev)).unwrap();
+        assert!(cqe.ok());
+        assert_eq!(&odd[4..15], b"EMU00000001");
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
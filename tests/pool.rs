@@ -0,0 +1,110 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg};
+use nvme_oxide::{Dma, DmaPool, Emu, HeapDma, NVMeDev, QPrio};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc
+};
+
+#[derive(Clone)]
+struct Counting(HeapDma, Arc<AtomicUsize>);
+
+impl Dma for Counting {
+    unsafe fn alloc(&self, size: usize) -> usize {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        return unsafe { self.0.alloc(size) };
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize) {
+        unsafe { self.0.free(addr, size) }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        return self.0.virt_to_phys(va);
+    }
+}
+
+#[test]
+fn hot_path_reuses_pooled_blocks() {
+    let emu = Emu::new(&emu_cfg(512, 8192));
+    let heap = HeapDma::new();
+    let cnt = Arc::new(AtomicUsize::new(0));
+    {
+        let mut c = cfg();
+        c.set_io_queues(2).set_io_depth(32);
+        let dev = NVMeDev::new(emu.clone(), Counting(heap.clone(), cnt.clone()), &c).unwrap();
+        let ctrl = dev.ctrl();
+        assert_eq!(ctrl.pool().capacity(), 2 + 64);
+
+        let ns = dev.ns(1).unwrap();
+        let len = 4096 * 40;
+        let mut v = vec![0u8; len + 4096];
+        let buf = aligned(&mut v, len);
+        let base = cnt.load(Ordering::SeqCst);
+        for i in 0..20u64 {
+            buf[0] = i as u8;
+            ns.write(i * 8, buf).unwrap();
+            ns.read(i * 8, buf).unwrap();
+            ns.trim(i, 4).unwrap();
+            ctrl.smart_log().unwrap();
+            ctrl.reg_nss().unwrap();
+        }
+        assert_eq!(cnt.load(Ordering::SeqCst), base);
+        assert_eq!(ctrl.pool().misses(), 0);
+        assert_eq!(ctrl.pool().pooled(), 66);
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
+
+#[test]
+fn queue_churn_keeps_reservation_bounded() {
+    let emu = Emu::new(&emu_cfg(512, 8192));
+    let heap = HeapDma::new();
+    {
+        let mut c = cfg();
+        c.set_io_queues(1).set_io_depth(32);
+        let dev = NVMeDev::new(emu.clone(), heap.clone(), &c).unwrap();
+        let ctrl = dev.ctrl();
+        let cap = ctrl.pool().capacity();
+        let pooled = ctrl.pool().pooled();
+
+        for _ in 0..50 {
+            ctrl.new_ioq(16, QPrio::Medium).unwrap();
+            let qid = *ctrl.io_qids().iter().max().unwrap();
+            assert_eq!(ctrl.pool().capacity(), cap + 16);
+            ctrl.rm_ioq(qid).unwrap();
+
+            let cqid = ctrl.create_cq(16, None).unwrap();
+            let sqid = ctrl.create_sq(cqid, 16, QPrio::Medium).unwrap();
+            ctrl.rm_ioq(sqid).unwrap();
+            ctrl.rm_cq(cqid).unwrap();
+
+            drop(ctrl.new_qpair(16, QPrio::Medium).unwrap());
+        }
+        assert_eq!(ctrl.pool().capacity(), cap);
+        assert_eq!(ctrl.pool().pooled(), pooled);
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
+
+#[test]
+fn unreserve_releases_blocks() {
+    let heap = HeapDma::new();
+    {
+        let pool = DmaPool::new(&Arc::new(heap.clone()));
+        pool.reserve(8).unwrap();
+        pool.unreserve(3);
+        assert_eq!((pool.capacity(), pool.pooled()), (5, 5));
+        assert_eq!(heap.outstanding(), 5);
+
+        let addr = unsafe { pool.alloc(4096) };
+        pool.unreserve(10);
+        assert_eq!((pool.capacity(), pool.pooled()), (0, 0));
+        unsafe { pool.free(addr, 4096) };
+        assert_eq!(pool.pooled(), 0);
+    }
+    assert_eq!(heap.outstanding(), 0);
+}
@@ -0,0 +1,115 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg};
+use nvme_oxide::{Emu, HeapDma, NVMeError, QPrio};
+use std::thread;
+
+#[test]
+fn exclusive_pairs_per_thread() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        let shared = ctrl.io_qids();
+
+        let threads = (0..4u64).map(|t| {
+            let (ns, ctrl) = (dev.ns(1).unwrap(), ctrl.clone());
+            return thread::spawn(move || {
+                let mut qp = ctrl.new_qpair(64, QPrio::Medium).unwrap();
+                assert!(!ctrl.io_qids().contains(&qp.qid()));
+                let mut v = vec![0u8; 3 * 4096];
+                let buf = aligned(&mut v, 2 * 4096);
+                for i in 0..500 {
+                    let lba = t * 100 + i % 50;
+                    buf.fill((lba % 200) as u8 + 1);
+                    ns.write_qp(&mut qp, lba, &buf[..1024]).unwrap();
+                    buf.fill(0);
+                    ns.read_qp(&mut qp, lba, &mut buf[..1024]).unwrap();
+                    assert!(buf[..1024].iter().all(|&b| b == (lba % 200) as u8 + 1));
+                }
+                ns.flush_qp(&mut qp).unwrap();
+                ns.compare_qp(&mut qp, t * 100 + 49, &buf[..1024]).unwrap();
+                return qp.qid();
+            });
+        }).collect::<Vec<_>>();
+
+        let qids = threads.into_iter().map(|t| t.join().unwrap()).collect::<Vec<u16>>();
+        assert_eq!(ctrl.io_qids(), shared);
+        assert!(qids.iter().all(|&qid| ctrl.io_queue(qid).is_none()));
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn idle_pair_survives_reset() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        let ns = dev.ns(1).unwrap();
+        let mut qp = ctrl.new_qpair(16, QPrio::Medium).unwrap();
+
+        ctrl.reset().unwrap();
+        let mut v = vec![0u8; 2 * 4096];
+        let buf = aligned(&mut v, 4096);
+        ns.read_qp(&mut qp, 0, &mut buf[..512]).unwrap();
+
+        ctrl.reset().unwrap();
+        ns.flush_qp(&mut qp).unwrap();
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn pair_uses_its_own_prp_pool() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    let ns = dev.ns(1).unwrap();
+    let mut qp = ctrl.new_qpair(16, QPrio::Medium).unwrap();
+    assert_eq!(qp.pool().capacity(), 16);
+
+    let misses = ctrl.pool().misses();
+    let pooled = ctrl.pool().pooled();
+    let mut v = vec![0u8; 5 * 4096];
+    let buf = aligned(&mut v, 4 * 4096);
+    for _ in 0..8 {
+        ns.write_qp(&mut qp, 0, buf).unwrap();
+    }
+    assert_eq!((ctrl.pool().misses(), ctrl.pool().pooled()), (misses, pooled));
+    assert_eq!((qp.pool().misses(), qp.pool().pooled()), (0, 16));
+}
+
+#[test]
+fn qids_bounded_by_granted_queues() {
+    let mut ecfg = emu_cfg(512, 64);
+    ecfg.mqes = 0xFFFF;
+    ecfg.nqs = 4;
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        assert_eq!(ctrl.data().mqe, u16::MAX);
+        assert_eq!((ctrl.nsq(), ctrl.ncq()), (4, 4));
+
+        let mut pairs = Vec::new();
+        loop {
+            match ctrl.new_qpair(64, QPrio::Medium) {
+                Ok(qp) => pairs.push(qp),
+                Err(NVMeError::FullQp) => break,
+                Err(e) => panic!("{e:?}")
+            }
+        }
+        assert_eq!(ctrl.io_qids().len() + pairs.len(), 4);
+        assert!(pairs.iter().all(|qp| qp.qid() <= 4));
+        assert!(matches!(ctrl.create_cq(64, None), Err(NVMeError::FullQp)));
+        dev.ns(1).unwrap().flush().unwrap();
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
@@ -0,0 +1,53 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg};
+use nvme_oxide::{Emu, HeapDma, NVMeError};
+
+#[test]
+fn fatal_status_recovers() {
+    let emu = Emu::new(&emu_cfg(512, 1024));
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        assert_eq!(ctrl.set_ioq_cnt(3).unwrap(), 3);
+        ctrl.en_async_ev().unwrap();
+        let aec = ctrl.get_feat(0x0B).unwrap();
+        let nq = ctrl.get_feat(0x07).unwrap();
+
+        let ns = dev.ns(1).unwrap();
+        let mut v = vec![0u8; 2 * 4096];
+        let buf = aligned(&mut v, 512);
+        for _ in 0..6 {
+            ns.write(0, buf).unwrap();
+        }
+
+        emu.set_fatal();
+        assert!(matches!(ns.flush(), Err(NVMeError::Reset)));
+        assert_eq!(emu.resets(), 1);
+
+        for _ in 0..12 {
+            ns.read(0, buf).unwrap();
+        }
+        assert_eq!(ctrl.ioq_cnt(), 3);
+        assert_eq!(ctrl.get_feat(0x0B).unwrap(), aec);
+        assert_eq!(ctrl.get_feat(0x07).unwrap(), nq);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn explicit_reset_keeps_queues() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    let qids = ctrl.io_qids();
+
+    ctrl.reset().unwrap();
+    assert_eq!(emu.resets(), 1);
+    assert_eq!(ctrl.io_qids(), qids);
+    dev.ns(1).unwrap().flush().unwrap();
+}
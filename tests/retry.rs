@@ -0,0 +1,104 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, dev, emu_cfg};
+use nvme_oxide::{CmdClass, Emu, HeapDma, NVMeDev, NVMeError, QPrio, RetryPolicy, StatusCode};
+use std::time::{Duration, Instant};
+
+#[test]
+fn retries_honour_dnr_and_crd() {
+    let mut ecfg = emu_cfg(512, 64);
+    ecfg.crdt = [2, 0, 0];
+    let emu = Emu::new(&ecfg);
+    let mut c = cfg();
+    let mut rp = RetryPolicy::new();
+    rp.set_retries(CmdClass::Read, 3);
+    c.set_retry(rp);
+    let dev = NVMeDev::new(emu.clone(), HeapDma::new(), &c).unwrap();
+    let ctrl = dev.ctrl();
+    assert!(ctrl.acre());
+    assert_eq!(ctrl.data().crdt, [2, 0, 0]);
+
+    let ns = dev.ns(1).unwrap();
+    let mut v = vec![0u8; 8192];
+    let buf = aligned(&mut v, 512);
+
+    emu.fail_io(0x082 | (1 << 11), 2);
+    let start = Instant::now();
+    ns.read(0, buf).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    emu.fail_io(0x082, 1);
+    match ns.write(0, buf).unwrap_err().err {
+        NVMeError::CmdFail(s) => assert_eq!(s.code(), StatusCode::NsNotReady),
+        e => panic!("unexpected error {e}")
+    }
+
+    emu.fail_io(0x082 | 0x4000, 1);
+    assert!(ns.read(0, buf).is_err());
+    ns.read(0, buf).unwrap();
+
+    emu.fail_io(0x082, 4);
+    assert!(ns.read(0, buf).is_err());
+    ns.read(0, buf).unwrap();
+
+    emu.fail_io(0x300, 1);
+    ns.read(0, buf).unwrap();
+
+    ctrl.reset().unwrap();
+    assert!(ctrl.acre());
+    emu.fail_io(0x082 | (1 << 11), 1);
+    ns.read(0, buf).unwrap();
+}
+
+#[test]
+fn no_retries_by_default() {
+    let mut ecfg = emu_cfg(512, 64);
+    ecfg.crdt = [2, 0, 0];
+    let emu = Emu::new(&ecfg);
+    let dev = dev(&emu, &HeapDma::new());
+    assert!(!dev.ctrl().acre());
+
+    emu.fail_io(0x082 | (1 << 11), 1);
+    let mut v = vec![0u8; 8192];
+    match dev.ns(1).unwrap().read(0, aligned(&mut v, 512)).unwrap_err().err {
+        NVMeError::CmdFail(s) => assert!(s.crd == 0 && !s.dnr),
+        e => panic!("unexpected error {e}")
+    }
+}
+
+#[test]
+fn only_listed_statuses_and_idempotent_admin_retry() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let mut c = cfg();
+    let mut rp = RetryPolicy::new();
+    rp.set_retries(CmdClass::Read, 3).set_retries(CmdClass::Admin, 3);
+    c.set_retry(rp);
+    let dev = NVMeDev::new(emu.clone(), HeapDma::new(), &c).unwrap();
+    let ctrl = dev.ctrl();
+    let ns = dev.ns(1).unwrap();
+    let mut v = vec![0u8; 8192];
+    let buf = aligned(&mut v, 512);
+
+    emu.fail_io(0x371, 1);
+    match ns.read(0, buf).unwrap_err().err {
+        NVMeError::CmdFail(s) => assert_eq!(s.code(), StatusCode::AbortedByHost),
+        e => panic!("unexpected error {e}")
+    }
+    emu.fail_io(0x022, 1);
+    assert!(ns.read(0, buf).is_err());
+    emu.fail_io(0x303, 1);
+    ns.read(0, buf).unwrap();
+
+    emu.fail_admin(0x0A, 0x082, 1);
+    ctrl.get_feat(0x07).unwrap();
+    for opc in [0x09, 0x05, 0x01] {
+        emu.fail_admin(opc, 0x082, 1);
+    }
+    assert!(ctrl.set_feat(0x08, 0).is_err());
+    assert!(ctrl.create_cq(16, None).is_err());
+    let cqid = ctrl.create_cq(16, None).unwrap();
+    assert!(ctrl.create_sq(cqid, 16, QPrio::Medium).is_err());
+    ctrl.create_sq(cqid, 16, QPrio::Medium).unwrap();
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg, pattern};
+use nvme_oxide::{build_sgl, Cmd, Emu, HeapDma, NVMeError, SglDesc, PSDT_SGL};
+
+#[test]
+fn sgl_support_gates_unaligned_buffers() {
+    for (sgls, shift, ok) in [(0u32, 1usize, false), (1, 1, true), (2, 1, false), (2, 4, true), (1, 0, true)] {
+        let mut ecfg = emu_cfg(512, 4096);
+        ecfg.sgls = sgls | (1 << 16);
+        let emu = Emu::new(&ecfg);
+        let dma = HeapDma::new();
+        {
+            let dev = dev(&emu, &dma);
+            let ns = dev.ns(1).unwrap();
+            let len = 512 * 40;
+            let mut v = vec![0u8; len + 8192];
+            let buf = &mut aligned(&mut v, len + shift)[shift..];
+            pattern(buf, shift as u8);
+
+            let res = ns.write(3, buf);
+            assert_eq!(res.is_ok(), ok, "sgls {sgls} shift {shift}");
+            if !ok {
+                assert!(matches!(res.unwrap_err().err, NVMeError::InvBuf));
+                continue;
+            }
+
+            let mut v2 = vec![0u8; len + 8192];
+            let out = &mut aligned(&mut v2, len + shift)[shift..];
+            ns.read(3, out).unwrap();
+            assert!(buf == out);
+        }
+        assert_eq!(dma.outstanding(), 0);
+    }
+}
+
+#[test]
+fn bit_bucket_skips_data() {
+    let mut ecfg = emu_cfg(512, 4096);
+    ecfg.sgls = 1 | (1 << 16);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        let ns = dev.ns(1).unwrap();
+        let mut v = vec![0u8; 512 * 5 + 4096];
+        let buf = aligned(&mut v, 512 * 5);
+        pattern(buf, 3);
+        ns.write(3, buf).unwrap();
+
+        let mut a = vec![0u8; 512];
+        let mut b = vec![0u8; 1024];
+        let descs = [
+            SglDesc::data(a.as_mut_ptr() as u64, 512),
+            SglDesc::bit_bucket(1024),
+            SglDesc::data(b.as_mut_ptr() as u64, 1024)
+        ];
+        let (d1, d2, list) = build_sgl(&dma, &descs).unwrap();
+        assert!(list.is_some());
+        ctrl.io_cmd(&Cmd::read(1, 3, 5, d1, d2).with_psdt(PSDT_SGL)).unwrap();
+        list.unwrap().free(&dma);
+        assert!(a[..] == buf[..512]);
+        assert!(b[..] == buf[1536..2560]);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
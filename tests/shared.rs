@@ -0,0 +1,82 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, emu_cfg};
+use nvme_oxide::{Cmd, Ctrl, Emu, HeapDma, Ns, StdClock};
+use std::{sync::Arc, thread};
+
+#[test]
+fn concurrent_submitters_share_a_queue() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    {
+        let ctrl = Arc::new(Ctrl::new(emu.clone(), dma.clone(), &cfg()).unwrap());
+        ctrl.set_io_timeout(5_000_000);
+        let ns = Arc::new(Ns::new(ctrl.clone(), 1).unwrap());
+        let q = ctrl.io_queue(ctrl.io_qids()[0]).unwrap();
+
+        let threads = (0..8u64).map(|t| {
+            let (ns, q, ctrl) = (ns.clone(), q.clone(), ctrl.clone());
+            return thread::spawn(move || {
+                let mut v = vec![0u8; 2 * 4096];
+                let buf = aligned(&mut v, 4096);
+                let clock = StdClock::new();
+                for i in 0..2000 {
+                    let lba = t * 64 + i % 64;
+                    buf[..512].fill((lba % 251) as u8);
+                    ns.write(lba, &buf[..512]).unwrap();
+
+                    let cmd = Cmd::read(1, lba, 1, buf.as_ptr() as u64, 0);
+                    let c = q.submit_async(&cmd, ctrl.mmio(), ctrl.dstrd()).unwrap();
+                    c.wait(&clock, 5_000_000).unwrap();
+                    assert!(buf[..512].iter().all(|&b| b == (lba % 251) as u8));
+                }
+            });
+        }).collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(q.is_idle());
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn saturated_queue_never_loses_completions() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    for depth in 2..=4 {
+        let mut c = cfg();
+        c.set_io_queues(1).set_io_depth(depth);
+        let ctrl = Arc::new(Ctrl::new(emu.clone(), dma.clone(), &c).unwrap());
+        ctrl.set_io_timeout(2_000_000);
+        ctrl.set_block_on_full(true);
+        let ns = Arc::new(Ns::new(ctrl.clone(), 1).unwrap());
+        let resets = emu.resets();
+
+        let threads = (0..16u64).map(|t| {
+            let ns = ns.clone();
+            return thread::spawn(move || {
+                let mut v = vec![0u8; 2 * 4096];
+                let buf = aligned(&mut v, 512);
+                for i in 0..500 {
+                    let lba = t * 16 + i % 16;
+                    buf.fill((lba % 251) as u8);
+                    ns.write(lba, buf).unwrap();
+                    buf.fill(0);
+                    ns.read(lba, buf).unwrap();
+                    assert!(buf.iter().all(|&b| b == (lba % 251) as u8));
+                }
+            });
+        }).collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(emu.resets(), resets);
+        assert!(ctrl.io_queue(ctrl.io_qids()[0]).unwrap().is_idle());
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg};
+use nvme_oxide::{Cmd, Emu, HeapDma, NVMeError, QPrio, StdClock};
+
+#[test]
+fn sqs_share_one_cq() {
+    let emu = Emu::new(&emu_cfg(512, 4096));
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ctrl = dev.ctrl();
+        let ns = dev.ns(1).unwrap();
+
+        let cqid = ctrl.create_cq(64, Some(2)).unwrap();
+        assert!(matches!(ctrl.create_sq(1, 16, QPrio::Medium), Err(NVMeError::InvQp)));
+        let sqs = (0..3).map(|_| ctrl.create_sq(cqid, 32, QPrio::Medium).unwrap()).collect::<Vec<u16>>();
+        for &sqid in &sqs {
+            let q = ctrl.io_queue(sqid).unwrap();
+            assert_eq!((q.cqid(), q.vector()), (cqid, Some(2)));
+        }
+
+        let qa = ctrl.io_queue(sqs[0]).unwrap();
+        let qb = ctrl.io_queue(sqs[1]).unwrap();
+        let mut pending = [&qa, &qb, &qb]
+            .map(|q| q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap());
+        assert_eq!(ctrl.on_interrupt(2), 3);
+        for c in pending.iter_mut() {
+            assert!(c.try_get().unwrap().is_ok());
+        }
+        drop(pending);
+
+        let mut v = vec![0u8; 2 * 4096];
+        let buf = aligned(&mut v, 512);
+        for lba in 0..60 {
+            buf.fill(lba as u8);
+            ns.write(lba, buf).unwrap();
+        }
+        for lba in 0..60 {
+            ns.read(lba, buf).unwrap();
+            assert!(buf.iter().all(|&b| b == lba as u8));
+        }
+
+        ctrl.reset().unwrap();
+        let q = ctrl.io_queue(sqs[1]).unwrap();
+        assert_eq!(q.cqid(), cqid);
+        q.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap().wait(&StdClock::new(), 1_000_000).unwrap();
+
+        assert!(matches!(ctrl.rm_cq(cqid), Err(NVMeError::InvQp)));
+        drop((qa, qb, q));
+        for &sqid in &sqs {
+            ctrl.rm_ioq(sqid).unwrap();
+        }
+        ctrl.rm_cq(cqid).unwrap();
+        ns.flush().unwrap();
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn rm_cq_survives_fatal_status() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    let cqid = ctrl.create_cq(16, None).unwrap();
+
+    emu.set_fatal();
+    assert!(matches!(ctrl.rm_cq(cqid), Err(NVMeError::Reset)));
+    assert_eq!(emu.resets(), 1);
+    assert!(matches!(ctrl.rm_cq(cqid), Err(NVMeError::InvQp)));
+    dev.ns(1).unwrap().flush().unwrap();
+}
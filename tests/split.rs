@@ -0,0 +1,113 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, cfg, dev, pattern};
+use nvme_oxide::{build_prp, Emu, EmuCfg, HeapDma, LbaError, NVMeDev, NVMeError, QPrio, StatusCode};
+
+#[test]
+fn split_by_mdts() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mdts = 2;
+    ecfg.add_ns(512, 200_000);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ns = dev.ns(1).unwrap();
+        let len = 1 << 20;
+        let mut wv = vec![0u8; len + 4096];
+        let w = aligned(&mut wv, len);
+        pattern(w, 7);
+        ns.write(100, w).unwrap();
+
+        let mut rv = vec![0u8; len + 4096];
+        let r = aligned(&mut rv, len);
+        ns.read(100, r).unwrap();
+        assert_eq!(r, w);
+
+        ns.compare(100, w).unwrap();
+        w[5 * 16384 + 3] ^= 1;
+        let e = ns.compare(100, w).unwrap_err();
+        assert_eq!(e.lba, 100 + 5 * 32);
+        assert!(matches!(e.err, NVMeError::CmdFail(s) if s.code() == StatusCode::CompareFailure && s.dnr));
+
+        ns.verify(0, 150_000).unwrap();
+        assert_eq!(ns.verify(199_990, 20).unwrap_err().lba, 199_990);
+        assert!(matches!(ns.read(0, &mut r[..100]), Err(LbaError { err: NVMeError::InvBuf, .. })));
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn prp_list_is_chained() {
+    let dma = HeapDma::new();
+    let mut v = vec![0u8; (1 << 22) + 4096];
+    let buf = aligned(&mut v, 1 << 22);
+    let base = buf.as_ptr() as usize;
+
+    let (prp1, prp2, list) = build_prp(&dma, base, buf.len(), 4096).unwrap();
+    let list = list.unwrap();
+    assert_eq!(prp1, base as u64);
+    assert_eq!(prp2, list.addr as u64);
+
+    let ents = unsafe { std::slice::from_raw_parts(list.addr as *const u64, list.sz / 8) };
+    assert_eq!(ents[510], (base + 511 * 4096) as u64);
+    assert_eq!(ents[511], (list.addr + 4096) as u64);
+    assert_eq!(ents[512], (base + 512 * 4096) as u64);
+    assert_eq!(ents[1023], (base + 1023 * 4096) as u64);
+    list.free(&dma);
+}
+
+#[test]
+fn large_write_without_mdts() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mdts = 0;
+    ecfg.add_ns(512, 16384);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let dev = dev(&emu, &dma);
+        let ns = dev.ns(1).unwrap();
+        let len = 4 << 20;
+        let mut wv = vec![0u8; len + 4096];
+        let w = aligned(&mut wv, len);
+        pattern(w, 3);
+        ns.write(0, w).unwrap();
+        assert_eq!(&emu.ns_data(1).unwrap()[..len], &w[..]);
+
+        let mut rv = vec![0u8; len + 4096];
+        let r = aligned(&mut rv, len);
+        ns.read(0, r).unwrap();
+        assert_eq!(r, w);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}
+
+#[test]
+fn chunks_overlap_on_one_queue() {
+    let mut ecfg = EmuCfg::new();
+    ecfg.mdts = 1;
+    ecfg.add_ns(512, 4096);
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    let mut cfg = cfg();
+    cfg.set_io_queues(1);
+    let dev = NVMeDev::new(emu.clone(), dma.clone(), &cfg).unwrap();
+    let ctrl = dev.ctrl();
+    let ns = dev.ns(1).unwrap();
+    let qid = ctrl.io_qids()[0];
+
+    let mut v = vec![0u8; 17 * 4096];
+    let buf = aligned(&mut v, 16 * 4096);
+    pattern(buf, 9);
+    let dbs = emu.sq_doorbells(qid);
+    ns.write(0, buf).unwrap();
+    assert_eq!(emu.sq_doorbells(qid), dbs + 1);
+
+    let mut qp = ctrl.new_qpair(4, QPrio::Medium).unwrap();
+    let dbs = emu.sq_doorbells(qp.qid());
+    ns.read_qp(&mut qp, 0, buf).unwrap();
+    assert_eq!(emu.sq_doorbells(qp.qid()), dbs + 3);
+    assert_eq!(&emu.ns_data(1).unwrap()[..buf.len()], &buf[..]);
+}
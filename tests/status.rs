@@ -0,0 +1,34 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{aligned, dev, emu_cfg};
+use nvme_oxide::{Emu, HeapDma, NVMeError, Sct, Status, StatusCode};
+
+#[test]
+fn status_fields_decode() {
+    let s = Status::from_raw(0x4000 | 0x2000 | (1 << 11) | 0x280);
+    assert_eq!(s.sct, Sct::Media);
+    assert_eq!(s.code(), StatusCode::WriteFault);
+    assert!(s.dnr && s.more && s.crd == 1);
+    assert_eq!(Status::from_raw(s.raw()), s);
+    assert_eq!(s.to_string(), "write fault (sct Media, sc 0x80, crd 1, more, dnr)");
+    assert_eq!(Status::from_raw(0x102).code(), StatusCode::InvalidQsize);
+    assert_eq!(Status::from_raw(0x082).code(), StatusCode::NsNotReady);
+    assert_eq!(Status::from_raw(0x7FF).code(), StatusCode::Other);
+}
+
+#[test]
+fn failed_command_reports_status_and_lba() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dev = dev(&emu, &HeapDma::new());
+    let ns = dev.ns(1).unwrap();
+    let mut v = vec![0u8; 8192];
+    let err = ns.read(100, aligned(&mut v, 512)).unwrap_err();
+    match err.err {
+        NVMeError::CmdFail(s) => assert_eq!(s.code(), StatusCode::LbaOutOfRange),
+        e => panic!("unexpected error {e}")
+    }
+    assert_eq!(err.lba, 100);
+    assert_eq!(err.to_string(), "command failed: LBA out of range (sct Generic, sc 0x80, dnr) at LBA 100");
+}
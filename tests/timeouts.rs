@@ -0,0 +1,52 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{dev, emu_cfg};
+use nvme_oxide::{Cmd, CtrlConfig, Emu, HeapDma, NVMeDev, NVMeError, PollClock, StdClock};
+use std::time::{Duration, Instant};
+
+#[test]
+fn poll_clock_is_accepted() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dev = NVMeDev::new(emu, HeapDma::new(), &CtrlConfig::new(PollClock::new())).unwrap();
+    dev.ns(1).unwrap().flush().unwrap();
+}
+
+#[test]
+fn hung_enable_times_out() {
+    let mut cfg = emu_cfg(512, 64);
+    cfg.to = 1;
+    let emu = Emu::new(&cfg);
+    emu.set_hang(true);
+    let start = Instant::now();
+    let res = NVMeDev::new(emu, HeapDma::new(), &CtrlConfig::new(StdClock::new()));
+    assert!(matches!(res, Err(NVMeError::Timeout)));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(500) && elapsed < Duration::from_secs(5));
+}
+
+#[test]
+fn shutdown_and_rm_ioq_are_bounded() {
+    let emu = Emu::new(&emu_cfg(512, 64));
+    let dma = HeapDma::new();
+    let dev = dev(&emu, &dma);
+    let ctrl = dev.ctrl();
+    ctrl.set_io_timeout(20_000);
+
+    let qid = ctrl.io_qids()[0];
+    let queue = ctrl.io_queue(qid).unwrap();
+    emu.set_stall(qid, true);
+    let pending = queue.submit_async(&Cmd::flush(1), ctrl.mmio(), ctrl.dstrd()).unwrap();
+
+    let start = Instant::now();
+    assert!(matches!(ctrl.rm_ioq(qid), Err(NVMeError::Timeout)));
+    assert!(matches!(ctrl.shutdown(), Err(NVMeError::Timeout)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    emu.set_stall(qid, false);
+    pending.wait(&StdClock::new(), 1_000_000).unwrap();
+    ctrl.shutdown().unwrap();
+    ctrl.resume().unwrap();
+    ctrl.rm_ioq(qid).unwrap();
+}
@@ -0,0 +1,97 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::{cfg, emu_cfg};
+use nvme_oxide::{ArbConfig, ArbMode, Cmd, Emu, HeapDma, Mmio, NVMeDev, NVMeError, QPrio};
+
+#[test]
+fn arbitration_and_queue_priorities() {
+    for wrr in [true, false] {
+        let mut ecfg = emu_cfg(512, 4096);
+        ecfg.wrr = wrr;
+        let emu = Emu::new(&ecfg);
+        let dma = HeapDma::new();
+        {
+            let mut c = cfg();
+            c.set_arb(ArbMode::WeightedRoundRobin);
+            let dev = NVMeDev::new(emu.clone(), dma.clone(), &c);
+            if !wrr {
+                assert!(matches!(dev.err(), Some(NVMeError::InvCfg)));
+                continue;
+            }
+            let dev = dev.unwrap();
+            let ctrl = dev.ctrl();
+            assert_eq!(ctrl.arb_mode(), ArbMode::WeightedRoundRobin);
+            assert_eq!((emu.read32(0x14) >> 11) & 7, 1);
+
+            let mut a = ArbConfig::new();
+            a.set_burst(3).set_low(1).set_mid(4).set_high(16);
+            ctrl.set_arbitration(a).unwrap();
+            let got = ctrl.get_arbitration().unwrap();
+            assert_eq!((got.burst(), got.low(), got.mid(), got.high()), (3, 1, 4, 16));
+
+            let default = ctrl.io_qids();
+            ctrl.new_ioq(32, QPrio::High).unwrap();
+            assert_eq!(ctrl.io_qids(), default);
+            let high = ctrl.prio_qids(QPrio::High);
+            assert_eq!(high.len(), 1);
+            assert_eq!(emu.sq_prio(high[0]), Some(1));
+            assert_eq!(emu.sq_prio(default[0]), Some(2));
+            assert!(ctrl.prio_qids(QPrio::Low).is_empty());
+
+            let qp = ctrl.new_qpair(16, QPrio::Urgent).unwrap();
+            assert_eq!(emu.sq_prio(qp.qid()), Some(0));
+            ctrl.reset().unwrap();
+            assert_eq!(emu.sq_prio(high[0]), Some(1));
+            assert_eq!(ctrl.get_arbitration().unwrap().high(), 16);
+            drop(qp);
+        }
+        assert_eq!(dma.outstanding(), 0);
+    }
+}
+
+#[test]
+fn prioritized_queues_stay_out_of_default_pool() {
+    let mut ecfg = emu_cfg(512, 4096);
+    ecfg.wrr = true;
+    let emu = Emu::new(&ecfg);
+    let dma = HeapDma::new();
+    {
+        let mut c = cfg();
+        c.set_arb(ArbMode::WeightedRoundRobin);
+        let dev = NVMeDev::new(emu.clone(), dma.clone(), &c).unwrap();
+        let ctrl = dev.ctrl();
+
+        let default = ctrl.io_qids();
+        ctrl.new_ioq(16, QPrio::High).unwrap();
+        let high = ctrl.prio_qids(QPrio::High)[0];
+        let cqid = ctrl.create_cq(16, None).unwrap();
+        let low = ctrl.create_sq(cqid, 16, QPrio::Low).unwrap();
+        assert_eq!(ctrl.io_qids(), default);
+        assert_eq!(ctrl.prio_qids(QPrio::Low), vec![low]);
+        assert_eq!(ctrl.io_queue(low).unwrap().prio(), QPrio::Low);
+
+        for _ in 0..8 {
+            ctrl.io_cmd(&Cmd::flush(1)).unwrap();
+        }
+        assert_eq!(emu.sq_doorbells(high), 0);
+        assert_eq!(emu.sq_doorbells(low), 0);
+
+        ctrl.io_cmd_prio(QPrio::High, &Cmd::flush(1)).unwrap();
+        ctrl.io_cmd_prio(QPrio::Low, &Cmd::flush(1)).unwrap();
+        assert_eq!(emu.sq_doorbells(high), 1);
+        assert_eq!(emu.sq_doorbells(low), 1);
+        assert!(matches!(ctrl.io_cmd_prio(QPrio::Urgent, &Cmd::flush(1)), Err(NVMeError::InvQp)));
+
+        ctrl.reset().unwrap();
+        ctrl.io_cmd_prio(QPrio::High, &Cmd::flush(1)).unwrap();
+        ctrl.rm_ioq(high).unwrap();
+        ctrl.rm_ioq(low).unwrap();
+        ctrl.rm_cq(cqid).unwrap();
+        assert!(ctrl.prio_qids(QPrio::High).is_empty());
+        assert!(matches!(ctrl.io_cmd_prio(QPrio::High, &Cmd::flush(1)), Err(NVMeError::InvQp)));
+        assert_eq!(ctrl.io_qids(), default);
+    }
+    assert_eq!(dma.outstanding(), 0);
+}